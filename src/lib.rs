@@ -0,0 +1,1751 @@
+//! Aggregation and set operations on IPv4 and IPv6 netblocks.
+//!
+//! [`IpSet`] keeps a set of prefixes of one address family sorted and fully
+//! aggregated; [`IpSet4`] and [`IpSet6`] are the concrete instantiations.
+//! The free functions underneath it ([`aggregate_netblocks`],
+//! [`subtract_set`], [`intersect_sets`], ...) are public as well, for callers
+//! that manage their own vectors.  [`PrefixTrie`] keeps prefixes as given,
+//! without aggregating, for longest-prefix lookups.
+//!
+//! ```
+//! use raggre::{IpSet4, NetblockV4};
+//!
+//! let mut set: IpSet4 = ["10.0.0.0/24", "10.0.1.0/24"]
+//!     .iter()
+//!     .map(|s| s.parse::<NetblockV4>().unwrap())
+//!     .collect();
+//! assert_eq!(set.iter().map(|nb| nb.to_string()).collect::<Vec<_>>(), ["10.0.0.0/23"]);
+//!
+//! set.remove("10.0.1.128/25".parse().unwrap());
+//! assert!(set.contains(&"10.0.1.0/25".parse().unwrap()));
+//! assert!(!set.contains(&"10.0.1.200".parse().unwrap()));
+//! ```
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::error::Error;
+use std::fmt;
+use std::iter::Peekable;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::slice;
+use std::str::FromStr;
+
+// ---------------------------------------------------------------------------
+// Trait for generic aggregation and set operations
+// ---------------------------------------------------------------------------
+
+/// Operations shared by [`NetblockV4`] and [`NetblockV6`].
+pub trait Aggregateable: Ord + Copy + fmt::Debug + fmt::Display {
+    /// Address type of this family.
+    type Addr: Copy + Ord + fmt::Display;
+
+    /// Width of an address in bits.
+    const BITS: u8;
+
+    /// Length of the network prefix in bits.
+    fn prefix_len(&self) -> u8;
+
+    /// Return true if `other` is a subnet of `self`.
+    fn contains(&self, other: &Self) -> bool;
+
+    /// Combine sibling netblocks into one, if possible.
+    fn aggregate(&self, other: &Self) -> Option<Self>;
+
+    /// Return this netblock with every bit beyond the prefix cleared.
+    fn canonical(&self) -> Self;
+
+    /// First address covered by this prefix.
+    fn first_addr(&self) -> Self::Addr;
+
+    /// Last address covered by this prefix.
+    fn last_addr(&self) -> Self::Addr;
+
+    /// Smallest prefix containing both `self` and `other`.
+    fn common_supernet(&self, other: &Self) -> Self;
+
+    /// The prefix of length `len` containing this netblock, or the netblock
+    /// itself if it is already that short.
+    fn supernet(&self, len: u8) -> Self;
+
+    /// Split a netblock into its two child halves (prefix_len + 1).
+    /// Returns None if already at maximum prefix length.
+    fn split_halves(&self) -> Option<(Self, Self)>;
+
+    /// Format just the first address of this prefix.
+    fn display_start(&self) -> String;
+
+    /// Format just the last address of this prefix.
+    fn display_end(&self) -> String;
+
+    /// Format as network/netmask (family-specific mask representation).
+    fn display_netmask(&self) -> String;
+
+    /// Format as a Cisco-style "network wildcard" pair (IPv4); IPv6
+    /// implementations fall back to CIDR since wildcard masks are IPv4-only.
+    /// An IPv4 /0 also prints as CIDR, since "0.0.0.0 255.255.255.255" is
+    /// parsed back as a host netmask.
+    fn display_wildcard(&self) -> String;
+
+    /// Format as network/prefix with the network as a zero-padded hex
+    /// integer (e.g. 0x0a000000/8).
+    fn display_hex_cidr(&self) -> String;
+
+    /// First address covered by this prefix, as an unsigned integer.
+    fn first_int(&self) -> u128;
+
+    /// Last address covered by this prefix, as an unsigned integer.
+    fn last_int(&self) -> u128;
+
+    /// Return true if the last address of self is immediately followed
+    /// by the first address of `next` (i.e., they form a contiguous range).
+    fn is_contiguous_with(&self, next: &Self) -> bool;
+
+    /// Number of addresses covered by this prefix, as an exact u128.
+    /// Returns None only for the whole IPv6 space (/0), whose count is 2^128
+    /// and therefore one larger than u128::MAX.
+    fn address_count(&self) -> Option<u128>;
+}
+
+// ---------------------------------------------------------------------------
+// Custom error for parsing netblocks
+// ---------------------------------------------------------------------------
+
+/// Reason a string could not be parsed as a netblock or address range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetblockParseError {
+    /// Nothing to parse (empty line or field).
+    Empty,
+    /// A dotted-quad IPv4 octet is out of range or has a leading zero.
+    BadOctet(String),
+    /// Text is not an address of the expected family.
+    BadAddress(String),
+    /// The text after '/' is not a number.
+    BadPrefix(String),
+    /// Prefix length exceeds the family maximum (32 or 128).
+    PrefixTooLong { len: u32, max: u8 },
+    /// A netmask or wildcard mask whose bits are not contiguous.
+    NonContiguousMask(String),
+    /// The text expands to more prefixes than the configured limit allows.
+    TooManyPrefixes { count: u128, limit: u128 },
+    /// Bits beyond the prefix length are set (rejected with --ignore-invalid).
+    HostBitsSet,
+    /// Range text is not of the form START-END.
+    BadRange(String),
+    /// Range start is greater than range end.
+    RangeOrder,
+    /// Valid address of a family excluded by -4/-6.
+    WrongFamily,
+    /// Line is not valid UTF-8.
+    BadUtf8,
+}
+
+impl fmt::Display for NetblockParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty field"),
+            Self::BadOctet(o) => write!(f, "invalid IPv4 octet {:?}", o),
+            Self::BadAddress(a) => write!(f, "invalid address {:?}", a),
+            Self::BadPrefix(p) => write!(f, "invalid prefix length {:?}", p),
+            Self::PrefixTooLong { len, max } => {
+                write!(f, "prefix length /{} exceeds /{}", len, max)
+            }
+            Self::NonContiguousMask(m) => write!(f, "non-contiguous mask {:?}", m),
+            Self::TooManyPrefixes { count, limit } => {
+                write!(f, "expands to {} prefixes, more than the limit of {}", count, limit)
+            }
+            Self::HostBitsSet => write!(f, "host bits set beyond prefix length"),
+            Self::BadRange(r) => write!(f, "invalid range {:?} (expected START-END)", r),
+            Self::RangeOrder => write!(f, "range start is greater than range end"),
+            Self::WrongFamily => write!(f, "address family not selected"),
+            Self::BadUtf8 => write!(f, "invalid UTF-8"),
+        }
+    }
+}
+
+impl Error for NetblockParseError {}
+
+/// Parse the prefix length after '/', checking it against the family maximum.
+fn parse_prefix_len(s: &str, max: u8) -> Result<u8, NetblockParseError> {
+    let len = s.parse::<u32>().map_err(|_| NetblockParseError::BadPrefix(s.to_string()))?;
+    if len > max as u32 {
+        return Err(NetblockParseError::PrefixTooLong { len, max });
+    }
+    Ok(len as u8)
+}
+
+/// Parse a dotted-quad IPv4 address, naming the offending octet on failure.
+fn parse_ipv4_addr(s: &str) -> Result<Ipv4Addr, NetblockParseError> {
+    Ipv4Addr::from_str(s).map_err(|_| {
+        let octets: Vec<&str> = s.split('.').collect();
+        let numeric = |o: &&str| !o.is_empty() && o.bytes().all(|b| b.is_ascii_digit());
+        if octets.len() == 4 && octets.iter().all(numeric) {
+            // Shape is right, so some octet is > 255 or zero-padded
+            let bad = octets
+                .iter()
+                .find(|o| o.len() > 1 && (o.starts_with('0') || o.parse::<u8>().is_err()));
+            NetblockParseError::BadOctet(bad.unwrap_or(&s).to_string())
+        } else {
+            NetblockParseError::BadAddress(s.to_string())
+        }
+    })
+}
+
+/// Parse an IPv6 address.
+fn parse_ipv6_addr(s: &str) -> Result<Ipv6Addr, NetblockParseError> {
+    Ipv6Addr::from_str(s).map_err(|_| NetblockParseError::BadAddress(s.to_string()))
+}
+
+/// Prefix length of a `bits`-wide netmask (ones followed by zeros), or None
+/// if its bits are not contiguous.
+fn netmask_len(mask: u128, bits: u32) -> Option<u8> {
+    let full = u128::MAX >> (128 - bits);
+    let host = !mask & full;
+    (host & host.wrapping_add(1) == 0).then(|| (bits - host.count_ones()) as u8)
+}
+
+/// Prefix length of a `bits`-wide wildcard mask (zeros followed by ones),
+/// or None if its bits are not contiguous.
+fn wildcard_len(wildcard: u128, bits: u32) -> Option<u8> {
+    (wildcard & wildcard.wrapping_add(1) == 0).then(|| (bits - wildcard.count_ones()) as u8)
+}
+
+/// Parse an address written as an unsigned integer: decimal, or hex with a
+/// "0x" prefix.  The family is up to the caller.
+pub fn parse_int_addr(s: &str) -> Result<u128, NetblockParseError> {
+    let bad = || NetblockParseError::BadAddress(s.to_string());
+    let (digits, radix) = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => (hex, 16),
+        None => (s, 10),
+    };
+    // from_str_radix would also accept a leading '+'
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(bad());
+    }
+    u128::from_str_radix(digits, radix).map_err(|_| bad())
+}
+
+/// Split "ADDR MASK" at the whitespace between the two parts; None if there
+/// is no whitespace or the first part is itself a prefix.
+fn split_address_mask(s: &str) -> Option<(&str, &str)> {
+    s.split_once(char::is_whitespace)
+        .filter(|(addr, _)| !addr.contains('/'))
+        .map(|(addr, mask)| (addr, mask.trim_start()))
+}
+
+// ---------------------------------------------------------------------------
+// NetblockV4
+// ---------------------------------------------------------------------------
+
+/// An IPv4 prefix.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+pub struct NetblockV4 {
+    network: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl NetblockV4 {
+    /// Zero out bits beyond `prefix_len`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `prefix_len` is greater than 32; see [`NetblockV4::try_new`].
+    #[inline]
+    pub fn new(network: Ipv4Addr, prefix_len: u8) -> Self {
+        assert!(prefix_len <= 32, "IPv4 prefix length /{} exceeds /32", prefix_len);
+        let masked = if prefix_len == 0 {
+            0
+        } else {
+            let shift = 32 - prefix_len;
+            (u32::from(network) >> shift) << shift
+        };
+        Self { network: Ipv4Addr::from(masked), prefix_len }
+    }
+
+    /// Like [`NetblockV4::new`], but a `prefix_len` greater than 32 is an error.
+    pub fn try_new(network: Ipv4Addr, prefix_len: u8) -> Result<Self, NetblockParseError> {
+        if prefix_len > 32 {
+            return Err(NetblockParseError::PrefixTooLong { len: prefix_len.into(), max: 32 });
+        }
+        Ok(Self::new(network, prefix_len))
+    }
+
+    /// Network address as stored; may carry host bits if parsed with `from_str`.
+    #[inline]
+    pub fn network(&self) -> Ipv4Addr {
+        self.network
+    }
+
+    /// Return true if both have the same prefix_len and differ only
+    /// in the last bit of the prefix (siblings in the address space).
+    #[inline]
+    fn aggregateable_with(&self, other: &NetblockV4) -> bool {
+        if self.prefix_len != other.prefix_len || self.prefix_len == 0 {
+            return false;
+        }
+        let shift = 32 - self.prefix_len;
+        let s = u32::from(self.network) >> shift;
+        let o = u32::from(other.network) >> shift;
+        (s ^ o) == 1 // Ensures they differ by EXACTLY the last bit of the prefix
+    }
+
+    /// Check if a network address is canonical (i.e., all bits beyond prefix are zero)
+    #[inline]
+    pub fn is_canonical(&self) -> bool {
+        if self.prefix_len == 0 {
+            return true;
+        }
+        let shift = 32 - self.prefix_len;
+        let bits = u32::from(self.network);
+        let masked = (bits >> shift) << shift;
+        masked == bits
+    }
+}
+
+impl Aggregateable for NetblockV4 {
+    type Addr = Ipv4Addr;
+    const BITS: u8 = 32;
+
+    #[inline]
+    fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Return true if `other` is a subnet of `self`.
+    #[inline]
+    fn contains(&self, other: &Self) -> bool {
+        if self.prefix_len > other.prefix_len {
+            return false;
+        }
+        if self.prefix_len == 0 {
+            return true; // /0 contains everything
+        }
+        let shift = 32 - self.prefix_len;
+        ((u32::from(self.network) ^ u32::from(other.network)) >> shift) == 0
+    }
+
+    /// Combine sibling netblocks into one with prefix_len - 1.
+    #[inline]
+    fn aggregate(&self, other: &Self) -> Option<Self> {
+        if self.aggregateable_with(other) {
+            Some(Self::new(self.network, self.prefix_len - 1))
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn common_supernet(&self, other: &Self) -> Self {
+        let diff = u32::from(self.network) ^ u32::from(other.network);
+        let len = (diff.leading_zeros() as u8).min(self.prefix_len).min(other.prefix_len);
+        Self::new(self.network, len)
+    }
+
+    #[inline]
+    fn supernet(&self, len: u8) -> Self {
+        Self::new(self.network, len.min(self.prefix_len))
+    }
+
+    #[inline]
+    fn split_halves(&self) -> Option<(Self, Self)> {
+        if self.prefix_len >= 32 {
+            return None;
+        }
+        let new_len = self.prefix_len + 1;
+        let left = Self::new(self.network, new_len);
+        let right_bits = u32::from(self.network) | (1u32 << (31 - self.prefix_len));
+        let right = Self::new(Ipv4Addr::from(right_bits), new_len);
+        Some((left, right))
+    }
+
+    #[inline]
+    fn canonical(&self) -> Self {
+        Self::new(self.network, self.prefix_len)
+    }
+
+    #[inline]
+    fn first_addr(&self) -> Ipv4Addr {
+        self.network
+    }
+
+    #[inline]
+    fn last_addr(&self) -> Ipv4Addr {
+        let start = u32::from(self.network);
+        let end = if self.prefix_len == 0 {
+            u32::MAX
+        } else {
+            start | ((1u32 << (32 - self.prefix_len)) - 1)
+        };
+        Ipv4Addr::from(end)
+    }
+
+    fn display_start(&self) -> String {
+        format!("{}", self.network)
+    }
+
+    fn display_end(&self) -> String {
+        format!("{}", self.last_addr())
+    }
+
+    fn display_netmask(&self) -> String {
+        // prefix_len == 0 must be special-cased: `u32::MAX << 32` would overflow.
+        let mask = if self.prefix_len == 0 { 0 } else { u32::MAX << (32 - self.prefix_len) };
+        format!("{}/{}", self.network, Ipv4Addr::from(mask))
+    }
+
+    fn display_wildcard(&self) -> String {
+        if self.prefix_len == 0 {
+            return format!("{}/0", self.network);
+        }
+        let mask = u32::MAX << (32 - self.prefix_len);
+        let wildcard = !mask;
+        format!("{} {}", self.network, Ipv4Addr::from(wildcard))
+    }
+
+    fn display_hex_cidr(&self) -> String {
+        format!("0x{:08x}/{}", u32::from(self.network), self.prefix_len)
+    }
+
+    #[inline]
+    fn first_int(&self) -> u128 {
+        u32::from(self.network).into()
+    }
+
+    #[inline]
+    fn last_int(&self) -> u128 {
+        u32::from(self.last_addr()).into()
+    }
+
+    fn is_contiguous_with(&self, next: &Self) -> bool {
+        let start = u32::from(self.network);
+        let end = if self.prefix_len == 0 {
+            u32::MAX
+        } else {
+            start | ((1u32 << (32 - self.prefix_len)) - 1)
+        };
+        end < u32::MAX && end + 1 == u32::from(next.network)
+    }
+
+    #[inline]
+    fn address_count(&self) -> Option<u128> {
+        // IPv4 maxes out at 2^32 addresses, which always fits in u128.
+        Some(1u128 << (32 - self.prefix_len))
+    }
+}
+
+impl FromStr for NetblockV4 {
+    type Err = NetblockParseError;
+
+    /// Besides "ADDR" and "ADDR/LEN", accepts a netmask ("ADDR/255.255.255.0"
+    /// or "ADDR 255.255.255.0") and a Cisco wildcard mask ("ADDR 0.0.0.255").
+    /// After whitespace, masks that read both ways take the reading that
+    /// gives a host (/32): 0.0.0.0 as a Cisco wildcard and 255.255.255.255
+    /// as a Windows `route print` netmask, so neither widens to /0.
+    ///
+    /// Host bits are kept as written; see `is_canonical` and `canonical`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((ip_str, mask_str)) = split_address_mask(s) {
+            let ip = parse_ipv4_addr(ip_str)?;
+            let mask = u128::from(u32::from(parse_ipv4_addr(mask_str)?));
+            let prefix = wildcard_len(mask, 32)
+                .max(netmask_len(mask, 32))
+                .ok_or_else(|| NetblockParseError::NonContiguousMask(mask_str.to_string()))?;
+            Ok(Self { network: ip, prefix_len: prefix })
+        } else if let Some((ip_str, prefix_str)) = s.split_once('/') {
+            let ip = parse_ipv4_addr(ip_str)?;
+            let prefix = if prefix_str.contains('.') {
+                let mask = u32::from(parse_ipv4_addr(prefix_str)?);
+                netmask_len(u128::from(mask), 32)
+                    .ok_or_else(|| NetblockParseError::NonContiguousMask(prefix_str.to_string()))?
+            } else {
+                parse_prefix_len(prefix_str, 32)?
+            };
+            Ok(Self { network: ip, prefix_len: prefix })
+        } else {
+            // No /prefix => assume /32
+            Ok(Self { network: parse_ipv4_addr(s)?, prefix_len: 32 })
+        }
+    }
+}
+
+impl fmt::Display for NetblockV4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Convert an arbitrary IPv4 range [start, end] into the minimal set of CIDR prefixes.
+pub fn range_to_prefixes_v4(start: u32, end: u32) -> Vec<NetblockV4> {
+    let mut prefixes = Vec::new();
+    let mut cur = start;
+
+    while cur <= end {
+        // Find how many trailing zero bits `cur` has — this limits alignment
+        let trailing = if cur == 0 { 32 } else { cur.trailing_zeros() };
+
+        // Find the largest block size (power of 2) that fits within [cur, end]
+        let max_size_bits = if end - cur == u32::MAX { 32 } else { (end - cur + 1).ilog2() };
+
+        let bits = std::cmp::min(trailing, max_size_bits);
+        let prefix_len = 32 - bits as u8;
+
+        prefixes.push(NetblockV4::new(Ipv4Addr::from(cur), prefix_len));
+
+        // Advance past this block
+        let block_size: u64 = 1u64 << bits;
+        let next = cur as u64 + block_size;
+        if next > u32::MAX as u64 {
+            break; // We've covered through 255.255.255.255
+        }
+        cur = next as u32;
+    }
+
+    prefixes
+}
+
+/// Parse IPv4 "ADDR/NETMASK" or "ADDR WILDCARD" where the mask need not be
+/// contiguous.  Returns the address and the wildcard (free) bits, for
+/// [`wildcard_to_prefixes_v4`].
+pub fn parse_masked_v4(s: &str) -> Result<(u32, u32), NetblockParseError> {
+    if let Some((ip_str, mask_str)) = split_address_mask(s) {
+        Ok((u32::from(parse_ipv4_addr(ip_str)?), u32::from(parse_ipv4_addr(mask_str)?)))
+    } else if let Some((ip_str, mask_str)) = s.split_once('/')
+        && mask_str.contains('.')
+    {
+        Ok((u32::from(parse_ipv4_addr(ip_str)?), !u32::from(parse_ipv4_addr(mask_str)?)))
+    } else {
+        Err(NetblockParseError::BadAddress(s.to_string()))
+    }
+}
+
+/// Number of prefixes [`wildcard_to_prefixes_v4`] returns for `wildcard`:
+/// every free bit outside the trailing run doubles it.
+pub fn wildcard_prefix_count(wildcard: u32) -> u64 {
+    1u64 << (wildcard.count_ones() - wildcard.trailing_ones())
+}
+
+/// Expand `addr` with the `wildcard` bits free (a Cisco wildcard match) into
+/// the equivalent prefixes, in ascending order.  The trailing run of free
+/// bits becomes the host part of each prefix; every combination of the
+/// other free bits gives one prefix.
+pub fn wildcard_to_prefixes_v4(addr: u32, wildcard: u32) -> Vec<NetblockV4> {
+    let host_bits = wildcard.trailing_ones();
+    let spread = wildcard & u32::MAX.checked_shl(host_bits).unwrap_or(0);
+    let prefix_len = (32 - host_bits) as u8;
+    let base = addr & !wildcard;
+
+    let mut prefixes = Vec::with_capacity(wildcard_prefix_count(wildcard) as usize);
+    // Enumerate the subsets of `spread` in ascending order
+    let mut subset: u32 = 0;
+    loop {
+        prefixes.push(NetblockV4::new(Ipv4Addr::from(base | subset), prefix_len));
+        if subset == spread {
+            break;
+        }
+        subset = subset.wrapping_sub(spread) & spread;
+    }
+    prefixes
+}
+
+/// Parse an IPv4 range line of the form "A.B.C.D-E.F.G.H"
+pub fn parse_range_v4(s: &str) -> Result<(u32, u32), NetblockParseError> {
+    let (start_str, end_str) =
+        s.split_once('-').ok_or_else(|| NetblockParseError::BadRange(s.to_string()))?;
+    let start_u32 = u32::from(parse_ipv4_addr(start_str.trim())?);
+    let end_u32 = u32::from(parse_ipv4_addr(end_str.trim())?);
+    if start_u32 > end_u32 {
+        return Err(NetblockParseError::RangeOrder);
+    }
+    Ok((start_u32, end_u32))
+}
+
+// ---------------------------------------------------------------------------
+// nmap-style IPv4 patterns
+// ---------------------------------------------------------------------------
+
+/// An nmap-style IPv4 target pattern such as `10.0.0-3.*`,
+/// `192.168.1.10-50` or `172.16.*.1`: each of the four octets is `*`, a
+/// number, a range `N-M` or a comma-separated list of numbers and ranges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OctetPattern {
+    /// Sorted, merged inclusive value ranges of each octet.
+    octets: [Vec<(u8, u8)>; 4],
+}
+
+/// Parse one decimal octet of a pattern.
+fn parse_octet(s: &str) -> Result<u8, NetblockParseError> {
+    let numeric = !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match s.parse::<u8>() {
+        Ok(v) if numeric && (s.len() == 1 || !s.starts_with('0')) => Ok(v),
+        _ => Err(NetblockParseError::BadOctet(s.to_string())),
+    }
+}
+
+/// Parse the value set of one pattern octet into sorted, merged ranges.
+fn parse_octet_set(s: &str) -> Result<Vec<(u8, u8)>, NetblockParseError> {
+    if s == "*" {
+        return Ok(vec![(0, 255)]);
+    }
+    let mut ranges = Vec::new();
+    for part in s.split(',') {
+        let (lo, hi) = match part.split_once('-') {
+            Some((lo, hi)) => (parse_octet(lo)?, parse_octet(hi)?),
+            None => {
+                let v = parse_octet(part)?;
+                (v, v)
+            }
+        };
+        if lo > hi {
+            return Err(NetblockParseError::RangeOrder);
+        }
+        ranges.push((lo, hi));
+    }
+    ranges.sort_unstable();
+    let mut merged: Vec<(u8, u8)> = Vec::with_capacity(ranges.len());
+    for (lo, hi) in ranges {
+        match merged.last_mut() {
+            Some(last) if u16::from(lo) <= u16::from(last.1) + 1 => last.1 = last.1.max(hi),
+            _ => merged.push((lo, hi)),
+        }
+    }
+    Ok(merged)
+}
+
+impl OctetPattern {
+    /// Index of the last octet not matching every value; the octets after
+    /// it become the host part of the prefixes.
+    fn last_partial(&self) -> Option<usize> {
+        self.octets.iter().rposition(|set| set[..] != [(0, 255)])
+    }
+
+    /// Number of prefixes [`to_prefixes`](Self::to_prefixes) returns,
+    /// computed without expanding the pattern.
+    pub fn prefix_count(&self) -> u128 {
+        let Some(last) = self.last_partial() else {
+            return 1;
+        };
+        let values = |set: &Vec<(u8, u8)>| -> u128 {
+            set.iter().map(|&(lo, hi)| u128::from(hi - lo) + 1).sum()
+        };
+        let combinations: u128 = self.octets[..last].iter().map(values).product();
+        let per_combination: u128 = self.octets[last]
+            .iter()
+            .map(|&(lo, hi)| range_to_prefixes_v4(lo.into(), hi.into()).len() as u128)
+            .sum();
+        combinations * per_combination
+    }
+
+    /// Expand the pattern into prefixes, in ascending order.  Every value
+    /// combination of the octets before the last partial one gives one
+    /// address range per range of that octet.
+    pub fn to_prefixes(&self) -> Vec<NetblockV4> {
+        let Some(last) = self.last_partial() else {
+            return vec![NetblockV4::new(Ipv4Addr::UNSPECIFIED, 0)];
+        };
+        let shift = 8 * (3 - last) as u32;
+        let host = (1u32 << shift) - 1;
+
+        let mut prefixes = Vec::new();
+        // Odometer over the leading octets: each digit indexes a value
+        let mut digits: Vec<(usize, u8)> =
+            self.octets[..last].iter().map(|set| (0, set[0].0)).collect();
+        loop {
+            let base = digits
+                .iter()
+                .enumerate()
+                .fold(0u32, |acc, (i, &(_, v))| acc | u32::from(v) << (24 - 8 * i));
+            for &(lo, hi) in &self.octets[last] {
+                let start = base | u32::from(lo) << shift;
+                let end = base | u32::from(hi) << shift | host;
+                prefixes.extend(range_to_prefixes_v4(start, end));
+            }
+
+            // Advance the odometer, rightmost octet first
+            let mut i = digits.len();
+            loop {
+                if i == 0 {
+                    return prefixes;
+                }
+                i -= 1;
+                let set = &self.octets[i];
+                let (ref mut r, ref mut v) = digits[i];
+                if *v < set[*r].1 {
+                    *v += 1;
+                    break;
+                }
+                if *r + 1 < set.len() {
+                    *r += 1;
+                    *v = set[*r].0;
+                    break;
+                }
+                *r = 0;
+                *v = set[0].0;
+            }
+        }
+    }
+}
+
+impl FromStr for OctetPattern {
+    type Err = NetblockParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split('.').collect();
+        if parts.len() != 4 {
+            return Err(NetblockParseError::BadAddress(s.to_string()));
+        }
+        Ok(Self {
+            octets: [
+                parse_octet_set(parts[0])?,
+                parse_octet_set(parts[1])?,
+                parse_octet_set(parts[2])?,
+                parse_octet_set(parts[3])?,
+            ],
+        })
+    }
+}
+
+/// Parse an IPv4 prefix with its trailing zero octets left out, such as
+/// `10.1/16` or `10/8`.  Host bits are kept as written.
+pub fn parse_short_prefix_v4(s: &str) -> Result<NetblockV4, NetblockParseError> {
+    let (addr_str, prefix_str) =
+        s.split_once('/').ok_or_else(|| NetblockParseError::BadAddress(s.to_string()))?;
+    let parts: Vec<&str> = addr_str.split('.').collect();
+    if parts.len() > 4 {
+        return Err(NetblockParseError::BadAddress(addr_str.to_string()));
+    }
+    let mut octets = [0u8; 4];
+    for (octet, part) in octets.iter_mut().zip(parts) {
+        *octet = parse_octet(part)?;
+    }
+    let prefix_len = parse_prefix_len(prefix_str, 32)?;
+    Ok(NetblockV4 { network: Ipv4Addr::from(octets), prefix_len })
+}
+
+// ---------------------------------------------------------------------------
+// NetblockV6
+// ---------------------------------------------------------------------------
+
+/// An IPv6 prefix.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+pub struct NetblockV6 {
+    network: Ipv6Addr,
+    prefix_len: u8,
+}
+
+impl NetblockV6 {
+    /// Create a new `NetblockV6`, zeroing out bits beyond `prefix_len`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `prefix_len` is greater than 128; see [`NetblockV6::try_new`].
+    #[inline]
+    pub fn new(network: Ipv6Addr, prefix_len: u8) -> Self {
+        assert!(prefix_len <= 128, "IPv6 prefix length /{} exceeds /128", prefix_len);
+        // Shift-based masking
+        let masked = if prefix_len == 0 {
+            0
+        } else {
+            let shift = 128 - prefix_len;
+            let bits = u128::from(network);
+            (bits >> shift) << shift
+        };
+        Self { network: Ipv6Addr::from(masked), prefix_len }
+    }
+
+    /// Like [`NetblockV6::new`], but a `prefix_len` greater than 128 is an error.
+    pub fn try_new(network: Ipv6Addr, prefix_len: u8) -> Result<Self, NetblockParseError> {
+        if prefix_len > 128 {
+            return Err(NetblockParseError::PrefixTooLong { len: prefix_len.into(), max: 128 });
+        }
+        Ok(Self::new(network, prefix_len))
+    }
+
+    /// Network address as stored; may carry host bits if parsed with `from_str`.
+    #[inline]
+    pub fn network(&self) -> Ipv6Addr {
+        self.network
+    }
+
+    /// True if both have the same prefix_len and differ only in the last bit of that prefix.
+    #[inline]
+    fn aggregateable_with(&self, other: &NetblockV6) -> bool {
+        // /0 cannot be aggregated further, and prefixes must match
+        if self.prefix_len != other.prefix_len || self.prefix_len == 0 {
+            return false;
+        }
+        let shift = 128 - self.prefix_len;
+        let s = u128::from(self.network) >> shift;
+        let o = u128::from(other.network) >> shift;
+        // XORing them should result in exactly 1 if they are perfect siblings
+        (s ^ o) == 1
+    }
+
+    /// Check if an IPv6 address is canonical for its prefix (all bits beyond prefix are zero)
+    #[inline]
+    pub fn is_canonical(&self) -> bool {
+        if self.prefix_len == 0 {
+            return true;
+        }
+        let shift = 128 - self.prefix_len;
+        let bits = u128::from(self.network);
+        let masked = (bits >> shift) << shift;
+        masked == bits
+    }
+}
+
+impl Aggregateable for NetblockV6 {
+    type Addr = Ipv6Addr;
+    const BITS: u8 = 128;
+
+    #[inline]
+    fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// True if `other` is fully contained in `self`.
+    #[inline]
+    fn contains(&self, other: &Self) -> bool {
+        if self.prefix_len > other.prefix_len {
+            return false;
+        }
+        if self.prefix_len == 0 {
+            return true; // /0 contains all of IPv6 space
+        }
+        let shift = 128 - self.prefix_len;
+        ((u128::from(self.network) ^ u128::from(other.network)) >> shift) == 0
+    }
+
+    /// Merge two siblings into a single netblock with prefix_len - 1, if possible.
+    #[inline]
+    fn aggregate(&self, other: &Self) -> Option<Self> {
+        if self.aggregateable_with(other) {
+            // We create the bigger block by using `self.network` and prefix_len - 1
+            Some(Self::new(self.network, self.prefix_len - 1))
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn common_supernet(&self, other: &Self) -> Self {
+        let diff = u128::from(self.network) ^ u128::from(other.network);
+        let len = (diff.leading_zeros() as u8).min(self.prefix_len).min(other.prefix_len);
+        Self::new(self.network, len)
+    }
+
+    #[inline]
+    fn supernet(&self, len: u8) -> Self {
+        Self::new(self.network, len.min(self.prefix_len))
+    }
+
+    #[inline]
+    fn split_halves(&self) -> Option<(Self, Self)> {
+        if self.prefix_len >= 128 {
+            return None;
+        }
+        let new_len = self.prefix_len + 1;
+        let left = Self::new(self.network, new_len);
+        let right_bits = u128::from(self.network) | (1u128 << (127 - self.prefix_len));
+        let right = Self::new(Ipv6Addr::from(right_bits), new_len);
+        Some((left, right))
+    }
+
+    #[inline]
+    fn canonical(&self) -> Self {
+        Self::new(self.network, self.prefix_len)
+    }
+
+    #[inline]
+    fn first_addr(&self) -> Ipv6Addr {
+        self.network
+    }
+
+    #[inline]
+    fn last_addr(&self) -> Ipv6Addr {
+        let start = u128::from(self.network);
+        let end = if self.prefix_len == 0 {
+            u128::MAX
+        } else {
+            start | ((1u128 << (128 - self.prefix_len)) - 1)
+        };
+        Ipv6Addr::from(end)
+    }
+
+    fn display_start(&self) -> String {
+        format!("{}", self.network)
+    }
+
+    fn display_end(&self) -> String {
+        format!("{}", self.last_addr())
+    }
+
+    fn display_netmask(&self) -> String {
+        // prefix_len == 0 must be special-cased: `u128::MAX << 128` would overflow.
+        let mask = if self.prefix_len == 0 { 0 } else { u128::MAX << (128 - self.prefix_len) };
+        format!("{}/{}", self.network, Ipv6Addr::from(mask))
+    }
+
+    fn display_wildcard(&self) -> String {
+        // IPv6 has no Cisco-style wildcard-mask convention; fall back to CIDR.
+        format!("{}/{}", self.network, self.prefix_len)
+    }
+
+    fn display_hex_cidr(&self) -> String {
+        format!("0x{:032x}/{}", u128::from(self.network), self.prefix_len)
+    }
+
+    #[inline]
+    fn first_int(&self) -> u128 {
+        u128::from(self.network)
+    }
+
+    #[inline]
+    fn last_int(&self) -> u128 {
+        u128::from(self.last_addr())
+    }
+
+    fn is_contiguous_with(&self, next: &Self) -> bool {
+        let start = u128::from(self.network);
+        let end = if self.prefix_len == 0 {
+            u128::MAX
+        } else {
+            start | ((1u128 << (128 - self.prefix_len)) - 1)
+        };
+        end < u128::MAX && end + 1 == u128::from(next.network)
+    }
+
+    #[inline]
+    fn address_count(&self) -> Option<u128> {
+        // A /0 covers 2^128 addresses, which is one more than u128::MAX,
+        // so it cannot be represented; the caller treats None as 2^128.
+        if self.prefix_len == 0 { None } else { Some(1u128 << (128 - self.prefix_len)) }
+    }
+}
+
+impl FromStr for NetblockV6 {
+    type Err = NetblockParseError;
+
+    /// Besides "ADDR" and "ADDR/LEN", accepts a netmask written as an
+    /// address ("ADDR/ffff:ffff::").
+    ///
+    /// Host bits are kept as written; see `is_canonical` and `canonical`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((ip_str, prefix_str)) = s.split_once('/') {
+            let ip = parse_ipv6_addr(ip_str)?;
+            let prefix = if prefix_str.contains(':') {
+                let mask = u128::from(parse_ipv6_addr(prefix_str)?);
+                netmask_len(mask, 128)
+                    .ok_or_else(|| NetblockParseError::NonContiguousMask(prefix_str.to_string()))?
+            } else {
+                parse_prefix_len(prefix_str, 128)?
+            };
+            Ok(Self { network: ip, prefix_len: prefix })
+        } else {
+            // No prefix => assume /128
+            Ok(Self { network: parse_ipv6_addr(s)?, prefix_len: 128 })
+        }
+    }
+}
+
+impl fmt::Display for NetblockV6 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Convert an arbitrary IPv6 range [start, end] into the minimal set of CIDR prefixes.
+pub fn range_to_prefixes_v6(start: u128, end: u128) -> Vec<NetblockV6> {
+    let mut prefixes = Vec::new();
+    let mut cur = start;
+
+    while cur <= end {
+        // Find how many trailing zero bits `cur` has — this limits alignment
+        let trailing = if cur == 0 { 128 } else { cur.trailing_zeros() };
+
+        // Find the largest block size (power of 2) that fits within [cur, end]
+        let max_size_bits = if end - cur == u128::MAX { 128 } else { (end - cur + 1).ilog2() };
+
+        let bits = std::cmp::min(trailing, max_size_bits);
+        let prefix_len = 128 - bits as u8;
+
+        prefixes.push(NetblockV6::new(Ipv6Addr::from(cur), prefix_len));
+
+        // A /0 covers the entire space in one block; computing block_size below
+        // as `1u128 << 128` would overflow the shift, so stop once it is emitted.
+        if bits >= 128 {
+            break;
+        }
+
+        // Advance past this block
+        let block_size: u128 = 1u128 << bits;
+        match cur.checked_add(block_size) {
+            Some(next) => cur = next,
+            None => break, // We've covered through ffff:...:ffff
+        }
+    }
+
+    prefixes
+}
+
+/// Parse an IPv6 range line of the form "addr1-addr2"
+pub fn parse_range_v6(s: &str) -> Result<(u128, u128), NetblockParseError> {
+    let (start_str, end_str) =
+        s.split_once('-').ok_or_else(|| NetblockParseError::BadRange(s.to_string()))?;
+    let start_u128 = u128::from(parse_ipv6_addr(start_str.trim())?);
+    let end_u128 = u128::from(parse_ipv6_addr(end_str.trim())?);
+    if start_u128 > end_u128 {
+        return Err(NetblockParseError::RangeOrder);
+    }
+    Ok((start_u128, end_u128))
+}
+
+// ---------------------------------------------------------------------------
+// Generic aggregation — single implementation for both address families
+// ---------------------------------------------------------------------------
+
+/// Sort and remove contained (duplicate/subset) netblocks without merging
+/// siblings.  Used by --diff so that individual prefixes are compared as-is
+/// rather than being collapsed into larger blocks first.
+pub fn normalize_netblocks<T: Aggregateable>(mut netblocks: Vec<T>) -> Vec<T> {
+    if netblocks.len() <= 1 {
+        return netblocks;
+    }
+
+    netblocks.sort_unstable();
+
+    let mut deduped = Vec::with_capacity(netblocks.len());
+    let mut prev = netblocks[0];
+
+    for &current in &netblocks[1..] {
+        if !prev.contains(&current) {
+            deduped.push(prev);
+            prev = current;
+        }
+    }
+    deduped.push(prev);
+    deduped
+}
+
+/// Merge netblocks until no more merges are possible.
+///
+/// After the initial sort the blocks are merged in a single sweep: a stack
+/// holds the pending, already aggregated prefixes.  Sorting puts every
+/// container before the blocks it contains, so a block covered by an earlier
+/// one can only be covered by the top of the stack.  Each new block is
+/// pushed and then merged with the top while the two are siblings; a merge
+/// can enable another merge one level up, so merges cascade towards shorter
+/// prefixes.  Every block is pushed and popped at most once, so the sweep is
+/// O(n) and the whole function O(n log n), independent of how many levels
+/// the merges span.
+pub fn aggregate_netblocks<T: Aggregateable>(netblocks: Vec<T>) -> Vec<T> {
+    aggregate_netblocks_floor(netblocks, 0)
+}
+
+/// Like [`aggregate_netblocks`], but never merge siblings into a prefix
+/// shorter than `floor`.  Contained blocks are still dropped, and input
+/// blocks already shorter than `floor` are kept as they are.
+pub fn aggregate_netblocks_floor<T: Aggregateable>(mut netblocks: Vec<T>, floor: u8) -> Vec<T> {
+    if netblocks.len() <= 1 {
+        return netblocks;
+    }
+
+    // Sort once at the start
+    netblocks.sort_unstable();
+
+    let mut stack: Vec<T> = Vec::with_capacity(netblocks.len());
+    for &current in &netblocks {
+        if let Some(top) = stack.last()
+            && top.contains(&current)
+        {
+            // Duplicate or subset of an already accepted block
+            continue;
+        }
+
+        let mut merged = current;
+        while let Some(top) = stack.last() {
+            match top.aggregate(&merged) {
+                Some(parent) if parent.prefix_len() >= floor => {
+                    stack.pop();
+                    merged = parent;
+                }
+                _ => break,
+            }
+        }
+        stack.push(merged);
+    }
+    stack
+}
+
+/// Number of prefixes [`split_to_length`] would return for `blocks`, or
+/// None if that does not fit in a u128 (only possible for IPv6).
+pub fn split_count<T: Aggregateable>(blocks: &[T], len: u8) -> Option<u128> {
+    blocks.iter().try_fold(0u128, |total, nb| {
+        let pieces = match len.checked_sub(nb.prefix_len()) {
+            Some(bits) => 1u128.checked_shl(u32::from(bits))?,
+            None => 1,
+        };
+        total.checked_add(pieces)
+    })
+}
+
+/// Split every block shorter than `len` into its subnets of length `len`
+/// (longer blocks are kept as they are).  Sorted input gives sorted output.
+/// The result can be huge, so check [`split_count`] first.
+pub fn split_to_length<T: Aggregateable>(blocks: &[T], len: u8) -> Vec<T> {
+    let mut result = Vec::with_capacity(blocks.len());
+    let mut pending = Vec::new();
+    for &nb in blocks {
+        pending.push(nb);
+        while let Some(current) = pending.pop() {
+            match current.split_halves() {
+                Some((left, right)) if current.prefix_len() < len => {
+                    pending.push(right);
+                    pending.push(left);
+                }
+                _ => result.push(current),
+            }
+        }
+    }
+    result
+}
+
+// ---------------------------------------------------------------------------
+// Density promotion — replace well-populated prefixes by the whole prefix
+// ---------------------------------------------------------------------------
+
+/// How densely a prefix must be listed before [`promote_dense`] promotes it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PromoteThreshold {
+    /// At least this many units listed.
+    Count(u128),
+    /// At least this percentage (0-100) of the prefix's units listed.
+    Percent(f64),
+}
+
+/// A prefix added by [`promote_dense`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Promotion<T> {
+    /// The promoted prefix.
+    pub prefix: T,
+    /// Units of the prefix that were listed.
+    pub listed: u128,
+    /// Units the prefix consists of.
+    pub units: u128,
+    /// Addresses the prefix covers beyond the listed input.
+    pub added: u128,
+}
+
+/// Replace the input blocks inside each prefix of length `prefix_len` by the
+/// whole prefix when enough of its units are listed.  A unit is a prefix of
+/// length `unit_len` (e.g. /32 for IPv4 hosts, /64 for IPv6 subnets); it
+/// counts as listed when any input block overlaps it.  `prefix_len` must be
+/// between 1 and `unit_len`.  Blocks already as short as `prefix_len` are
+/// passed through, as are prefixes that are listed completely (exact
+/// aggregation merges those anyway).
+///
+/// Returns the sorted, deduplicated blocks with promotions applied, and the
+/// promotions made.  Runs in O(n log n) for the initial sort and O(n) after.
+pub fn promote_dense<T: Aggregateable>(
+    netblocks: Vec<T>,
+    prefix_len: u8,
+    unit_len: u8,
+    threshold: PromoteThreshold,
+) -> (Vec<T>, Vec<Promotion<T>>) {
+    debug_assert!(prefix_len >= 1 && prefix_len <= unit_len);
+    let blocks = normalize_netblocks(netblocks);
+    let units = 1u128 << (unit_len - prefix_len);
+    let needed = match threshold {
+        PromoteThreshold::Count(k) => k,
+        PromoteThreshold::Percent(p) => (units as f64 * p / 100.0).ceil() as u128,
+    }
+    .max(1);
+
+    let mut result = Vec::with_capacity(blocks.len());
+    let mut promotions = Vec::new();
+    let mut i = 0;
+    while i < blocks.len() {
+        if blocks[i].prefix_len() <= prefix_len {
+            result.push(blocks[i]);
+            i += 1;
+            continue;
+        }
+
+        // Blocks are sorted and disjoint, so those inside one prefix are
+        // consecutive
+        let prefix = blocks[i].supernet(prefix_len);
+        let mut end = i;
+        let mut listed: u128 = 0;
+        let mut covered: u128 = 0;
+        let mut last_unit: Option<T> = None;
+        while end < blocks.len() && prefix.contains(&blocks[end]) {
+            let nb = blocks[end];
+            // prefix_len >= 1, so neither count can be the whole IPv6 space
+            covered += nb.address_count().unwrap_or(u128::MAX);
+            if nb.prefix_len() <= unit_len {
+                listed += 1 << (unit_len - nb.prefix_len());
+            } else if last_unit.is_none_or(|u| !u.contains(&nb)) {
+                listed += 1;
+                last_unit = Some(nb.supernet(unit_len));
+            }
+            end += 1;
+        }
+
+        let size = prefix.address_count().unwrap_or(u128::MAX);
+        if listed >= needed && covered < size {
+            result.push(prefix);
+            promotions.push(Promotion { prefix, listed, units, added: size - covered });
+        } else {
+            result.extend_from_slice(&blocks[i..end]);
+        }
+        i = end;
+    }
+    (result, promotions)
+}
+
+// ---------------------------------------------------------------------------
+// Lossy aggregation — fit the result into an entry budget
+// ---------------------------------------------------------------------------
+
+/// Child of a `LossyNode`: an input block (by index) or another node.
+#[derive(Clone, Copy)]
+enum LossyChild {
+    Block(usize),
+    Node(usize),
+}
+
+/// Branching point of the trie built by `aggregate_lossy`: the smallest
+/// supernet of two neighbouring blocks, where two subtrees meet.
+struct LossyNode<T> {
+    prefix: T,
+    parent: Option<usize>,
+    children: [LossyChild; 2],
+    /// Output entries currently below this node.
+    entries: usize,
+    /// Addresses below this node not covered yet, i.e. the cost of
+    /// collapsing it; lowered as descendants are collapsed.
+    gap: u128,
+    /// Bumped whenever `gap` changes; stale heap entries are requeued.
+    version: u32,
+    /// Set once this node or one of its ancestors has been collapsed.
+    collapsed: bool,
+}
+
+/// Addresses in `prefix` that are not in `covered`.
+fn uncovered<T: Aggregateable>(prefix: &T, covered: u128) -> u128 {
+    match prefix.address_count() {
+        Some(size) => size - covered,
+        // 2^128 - covered, without forming 2^128 (covered is never zero here)
+        None => u128::MAX - covered + 1,
+    }
+}
+
+/// Aggregate `netblocks` exactly, then keep replacing groups of neighbouring
+/// blocks by their smallest common supernet until at most `max_entries`
+/// remain.  Each step collapses the supernet that adds the fewest addresses
+/// not present in the input.  Returns the result and the total number of
+/// such over-covered addresses.
+///
+/// The candidate supernets form a binary trie with one branching node per
+/// pair of neighbouring blocks, built in O(n) from the sorted list.  The
+/// cheapest node is taken from a heap; collapsing it lowers the cost of its
+/// ancestors, which are re-queued lazily, so the whole run is
+/// O(n·(W + log n)) for W-bit addresses.
+pub fn aggregate_lossy<T: Aggregateable>(netblocks: Vec<T>, max_entries: usize) -> (Vec<T>, u128) {
+    let blocks = aggregate_netblocks(netblocks);
+    if blocks.len() <= max_entries.max(1) {
+        return (blocks, 0);
+    }
+
+    // Cartesian tree over the supernets of neighbouring pairs: shorter
+    // prefixes sit closer to the root.
+    let mut nodes: Vec<LossyNode<T>> = Vec::with_capacity(blocks.len() - 1);
+    let mut stack: Vec<usize> = Vec::new();
+    for i in 0..blocks.len() - 1 {
+        let prefix = blocks[i].common_supernet(&blocks[i + 1]);
+        let idx = nodes.len();
+        let mut left = LossyChild::Block(i);
+        while let Some(&top) = stack.last() {
+            if nodes[top].prefix.prefix_len() <= prefix.prefix_len() {
+                break;
+            }
+            stack.pop();
+            left = LossyChild::Node(top);
+        }
+        if let Some(&top) = stack.last() {
+            nodes[top].children[1] = LossyChild::Node(idx);
+        }
+        nodes.push(LossyNode {
+            prefix,
+            parent: None,
+            children: [left, LossyChild::Block(i + 1)],
+            entries: 0,
+            gap: 0,
+            version: 0,
+            collapsed: false,
+        });
+        stack.push(idx);
+    }
+    let root = stack[0];
+
+    // Fill in parents, entry counts and gaps bottom-up: children always have
+    // longer prefixes than their parent.  The input blocks below any node
+    // never cover all of it (they would have been aggregated), so their
+    // address sum fits in u128 even for the IPv6 /0.
+    let mut covered = vec![0u128; nodes.len()];
+    let mut order: Vec<usize> = (0..nodes.len()).collect();
+    order.sort_unstable_by_key(|&i| Reverse(nodes[i].prefix.prefix_len()));
+    for &idx in &order {
+        for child in nodes[idx].children {
+            let (entries, addresses) = match child {
+                LossyChild::Block(b) => (1, blocks[b].address_count().unwrap_or(u128::MAX)),
+                LossyChild::Node(c) => {
+                    nodes[c].parent = Some(idx);
+                    (nodes[c].entries, covered[c])
+                }
+            };
+            nodes[idx].entries += entries;
+            covered[idx] += addresses;
+        }
+        nodes[idx].gap = uncovered(&nodes[idx].prefix, covered[idx]);
+    }
+
+    let mut heap: BinaryHeap<Reverse<(u128, usize, u32)>> =
+        nodes.iter().enumerate().map(|(idx, n)| Reverse((n.gap, idx, 0))).collect();
+
+    let mut entries = blocks.len();
+    let mut overcovered: u128 = 0;
+    while entries > max_entries {
+        let Some(Reverse((cost, idx, version))) = heap.pop() else {
+            break;
+        };
+        if nodes[idx].collapsed {
+            continue;
+        }
+        if nodes[idx].version != version {
+            // Cost dropped since this entry was queued; requeue it at the
+            // current cost (never lower than that of the descendant whose
+            // collapse lowered it, which holds a fresh entry)
+            heap.push(Reverse((nodes[idx].gap, idx, nodes[idx].version)));
+            continue;
+        }
+
+        let removed = nodes[idx].entries - 1;
+        entries -= removed;
+        overcovered += cost;
+
+        // Everything below is now represented by this node's prefix
+        let mut pending = vec![idx];
+        while let Some(n) = pending.pop() {
+            nodes[n].collapsed = true;
+            for child in nodes[n].children {
+                if let LossyChild::Node(c) = child
+                    && !nodes[c].collapsed
+                {
+                    pending.push(c);
+                }
+            }
+        }
+
+        // Every ancestor now has a smaller gap and fewer entries; only the
+        // parent is requeued here, the others when their stale entry pops
+        let mut parent = nodes[idx].parent;
+        while let Some(p) = parent {
+            let node = &mut nodes[p];
+            node.entries -= removed;
+            node.gap -= cost;
+            node.version += 1;
+            parent = node.parent;
+        }
+        if let Some(p) = nodes[idx].parent {
+            heap.push(Reverse((nodes[p].gap, p, nodes[p].version)));
+        }
+    }
+
+    // In-order walk, emitting the topmost collapsed node of each subtree
+    let mut result = Vec::with_capacity(entries);
+    let mut walk = vec![LossyChild::Node(root)];
+    while let Some(child) = walk.pop() {
+        match child {
+            LossyChild::Block(b) => result.push(blocks[b]),
+            LossyChild::Node(n) if nodes[n].collapsed => result.push(nodes[n].prefix),
+            LossyChild::Node(n) => {
+                walk.push(nodes[n].children[1]);
+                walk.push(nodes[n].children[0]);
+            }
+        }
+    }
+
+    // Collapsed supernets may now be siblings of their neighbours
+    (aggregate_netblocks(result), overcovered)
+}
+
+// ---------------------------------------------------------------------------
+// Set operations — generic for both address families
+// ---------------------------------------------------------------------------
+
+/// Append the parts of `block` not covered by `excludes` to `out`.
+/// `excludes` must be sorted, disjoint and all contained in `block`; the block
+/// is split in halves only along the paths leading to an excluded prefix, and
+/// the fragments are emitted in ascending order.
+fn subtract_within<T: Aggregateable>(block: T, excludes: &[T], out: &mut Vec<T>) {
+    if excludes.is_empty() {
+        out.push(block);
+        return;
+    }
+    if excludes[0].contains(&block) {
+        // Entire block is excluded
+        return;
+    }
+    // block strictly contains every exclude — split into halves and recurse
+    let (left, right) = block.split_halves().expect("block must be larger than excl");
+    let mid = excludes.partition_point(|e| left.contains(e));
+    subtract_within(left, &excludes[..mid], out);
+    subtract_within(right, &excludes[mid..], out);
+}
+
+/// Subtract all `excludes` from `blocks`, returning the remaining netblocks.
+///
+/// Both inputs must be sorted and free of overlaps (as returned by
+/// `aggregate_netblocks` or `normalize_netblocks`); so is the result.  Each
+/// block is split into the largest prefixes that avoid the excludes and the
+/// pieces are not merged again, so aggregated inputs give an aggregated
+/// result but merely normalized ones may leave mergeable neighbours.
+///
+/// The two lists are swept in step: excludes ending before the current block
+/// are skipped, an exclude covering the block drops it, and the run of
+/// excludes inside the block is carved out of it.  Each exclude is visited
+/// once and costs at most one split per prefix bit, so the sweep is
+/// O(n + m·W) for W-bit addresses.
+pub fn subtract_set<T: Aggregateable>(blocks: Vec<T>, excludes: &[T]) -> Vec<T> {
+    let mut result = Vec::with_capacity(blocks.len());
+    let mut j = 0;
+    for block in blocks {
+        // Skip excludes lying entirely before this block
+        while j < excludes.len() && excludes[j] < block && !excludes[j].contains(&block) {
+            j += 1;
+        }
+        if j < excludes.len() && excludes[j].contains(&block) {
+            // Keep the exclude: it may cover the following blocks as well
+            continue;
+        }
+        // Excludes nested in this block form a contiguous run starting at j
+        let k = j + excludes[j..].partition_point(|e| block.contains(e));
+        subtract_within(block, &excludes[j..k], &mut result);
+        j = k;
+    }
+    // The fragments of a block are maximal, so no siblings are created
+    // that were not already in `blocks`
+    result
+}
+
+/// Intersect two sets of netblocks, keeping only the overlapping regions.
+///
+/// Both inputs must be sorted and free of overlaps (as returned by
+/// `aggregate_netblocks` or `normalize_netblocks`); so is the result.  Every
+/// emitted block is copied from one of the inputs, never merged with its
+/// neighbour, so intersect two aggregated sets if the result must be
+/// aggregated too.
+///
+/// Two disjoint prefixes never partially overlap, so each step either emits
+/// the smaller of two nested blocks or skips the one that ends first; a
+/// single merge-join sweep runs in O(n + m).
+pub fn intersect_sets<T: Aggregateable>(a: &[T], b: &[T]) -> Vec<T> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    let mut j = 0;
+    while i < a.len() && j < b.len() {
+        let (x, y) = (a[i], b[j]);
+        if x.contains(&y) {
+            // y is fully covered; later blocks of b may still fall inside x
+            result.push(y);
+            j += 1;
+        } else if y.contains(&x) {
+            result.push(x);
+            i += 1;
+        } else if x < y {
+            // Disjoint, and x lies entirely before y
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    result
+}
+
+/// For every block of `blocks`, the indices of the `inputs` overlapping it.
+///
+/// `blocks` must be sorted and free of overlaps, as every result of the
+/// functions above is; `inputs` may be in any order and overlap each other.
+/// Each list is ordered by input address.  Finding the first candidate
+/// block is a binary search, so the cost is O(m log n) plus the number of
+/// overlaps reported.
+pub fn overlapping_inputs<T: Aggregateable>(blocks: &[T], inputs: &[T]) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..inputs.len()).collect();
+    order.sort_by_key(|&i| inputs[i]);
+    let mut result = vec![Vec::new(); blocks.len()];
+    for i in order {
+        let (first, last) = (inputs[i].first_int(), inputs[i].last_int());
+        let start = blocks.partition_point(|b| b.last_int() < first);
+        for (k, block) in blocks.iter().enumerate().skip(start) {
+            if block.first_int() > last {
+                break;
+            }
+            result[k].push(i);
+        }
+    }
+    result
+}
+
+// ---------------------------------------------------------------------------
+// IpSet — always-aggregated set of netblocks
+// ---------------------------------------------------------------------------
+
+/// A set of netblocks of one address family.
+///
+/// The blocks are kept sorted, free of overlaps and with all siblings merged,
+/// so iteration yields the same output `raggre` prints.  Building a set with
+/// `collect`/`extend` aggregates once; `insert` and `remove` re-aggregate on
+/// every call and are meant for small incremental changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpSet<T: Aggregateable> {
+    blocks: Vec<T>,
+}
+
+/// Set of IPv4 netblocks.
+pub type IpSet4 = IpSet<NetblockV4>;
+
+/// Set of IPv6 netblocks.
+pub type IpSet6 = IpSet<NetblockV6>;
+
+impl<T: Aggregateable> IpSet<T> {
+    /// Create an empty set.
+    pub fn new() -> Self {
+        Self { blocks: Vec::new() }
+    }
+
+    /// Build a set from arbitrary (possibly overlapping, non-canonical) netblocks.
+    pub fn from_vec(blocks: Vec<T>) -> Self {
+        Self { blocks: aggregate_netblocks(blocks.iter().map(T::canonical).collect()) }
+    }
+
+    /// Number of aggregated prefixes in the set.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Add a netblock to the set.
+    pub fn insert(&mut self, nb: T) {
+        self.extend(std::iter::once(nb));
+    }
+
+    /// Remove every address of `nb` from the set.
+    pub fn remove(&mut self, nb: T) {
+        let blocks = std::mem::take(&mut self.blocks);
+        self.blocks = subtract_set(blocks, &[nb.canonical()]);
+    }
+
+    /// Return true if every address of `nb` is in the set.
+    pub fn contains(&self, nb: &T) -> bool {
+        let nb = nb.canonical();
+        // Blocks are disjoint and sorted, so only the last block starting at or
+        // before `nb` can cover it.
+        let idx = self.blocks.partition_point(|b| *b <= nb);
+        idx > 0 && self.blocks[idx - 1].contains(&nb)
+    }
+
+    /// Addresses in `self` or in `other`.
+    pub fn union(&self, other: &Self) -> Self {
+        let mut blocks = Vec::with_capacity(self.blocks.len() + other.blocks.len());
+        blocks.extend_from_slice(&self.blocks);
+        blocks.extend_from_slice(&other.blocks);
+        Self { blocks: aggregate_netblocks(blocks) }
+    }
+
+    /// Addresses in `self` but not in `other`.
+    pub fn difference(&self, other: &Self) -> Self {
+        Self { blocks: subtract_set(self.blocks.clone(), &other.blocks) }
+    }
+
+    /// Addresses in both `self` and `other`.
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self { blocks: intersect_sets(&self.blocks, &other.blocks) }
+    }
+
+    /// Iterate over the aggregated prefixes in ascending order.
+    pub fn iter(&self) -> slice::Iter<'_, T> {
+        self.blocks.iter()
+    }
+
+    /// Iterate over maximal contiguous `(first, last)` address ranges.
+    pub fn ranges(&self) -> Ranges<'_, T> {
+        Ranges { blocks: self.blocks.iter().peekable() }
+    }
+
+    /// The aggregated prefixes as a sorted slice.
+    pub fn as_slice(&self) -> &[T] {
+        &self.blocks
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        self.blocks
+    }
+}
+
+impl<T: Aggregateable> Default for IpSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Aggregateable> FromIterator<T> for IpSet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self::from_vec(iter.into_iter().collect())
+    }
+}
+
+impl<T: Aggregateable> Extend<T> for IpSet<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let mut blocks = std::mem::take(&mut self.blocks);
+        blocks.extend(iter.into_iter().map(|nb| nb.canonical()));
+        self.blocks = aggregate_netblocks(blocks);
+    }
+}
+
+impl<T: Aggregateable> IntoIterator for IpSet<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.blocks.into_iter()
+    }
+}
+
+impl<'a, T: Aggregateable> IntoIterator for &'a IpSet<T> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.blocks.iter()
+    }
+}
+
+/// Iterator over the contiguous address ranges of an [`IpSet`].
+pub struct Ranges<'a, T: Aggregateable> {
+    blocks: Peekable<slice::Iter<'a, T>>,
+}
+
+impl<T: Aggregateable> Iterator for Ranges<'_, T> {
+    type Item = (T::Addr, T::Addr);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.blocks.next()?;
+        let mut last = first;
+        while let Some(next) = self.blocks.next_if(|nb| last.is_contiguous_with(nb)) {
+            last = next;
+        }
+        Some((first.first_addr(), last.last_addr()))
+    }
+}
+
+// ---------------------------------------------------------------------------
+// PrefixTrie — longest-prefix match over unaggregated entries
+// ---------------------------------------------------------------------------
+
+/// A binary trie of prefixes of one address family, each with the values
+/// inserted for it.
+///
+/// Unlike [`IpSet`] the entries are kept as inserted: nested prefixes stay
+/// separate, so a lookup can report the most specific entry covering an
+/// address or every entry that does.
+#[derive(Debug, Clone)]
+pub struct PrefixTrie<T: Aggregateable, V> {
+    /// Node 0 is the root (/0); children are indices into this vector,
+    /// with 0 meaning no child since the root is nobody's child.
+    nodes: Vec<TrieNode<T, V>>,
+    entries: usize,
+}
+
+#[derive(Debug, Clone)]
+struct TrieNode<T, V> {
+    children: [usize; 2],
+    entry: Option<(T, Vec<V>)>,
+}
+
+impl<T, V> TrieNode<T, V> {
+    fn new() -> Self {
+        TrieNode { children: [0, 0], entry: None }
+    }
+}
+
+impl<T: Aggregateable, V> PrefixTrie<T, V> {
+    /// Create an empty trie.
+    pub fn new() -> Self {
+        PrefixTrie { nodes: vec![TrieNode::new()], entries: 0 }
+    }
+
+    /// Number of distinct prefixes in the trie.
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Add `value` to the entry for `prefix` (host bits are ignored).
+    pub fn insert(&mut self, prefix: T, value: V) {
+        let prefix = prefix.canonical();
+        let mut node = 0;
+        for depth in 0..prefix.prefix_len() {
+            let bit = Self::bit(&prefix, depth);
+            if self.nodes[node].children[bit] == 0 {
+                self.nodes.push(TrieNode::new());
+                self.nodes[node].children[bit] = self.nodes.len() - 1;
+            }
+            node = self.nodes[node].children[bit];
+        }
+        let entry = self.nodes[node].entry.get_or_insert_with(|| (prefix, Vec::new()));
+        if entry.1.is_empty() {
+            self.entries += 1;
+        }
+        entry.1.push(value);
+    }
+
+    /// The most specific entry containing every address of `nb`.
+    pub fn longest_match(&self, nb: &T) -> Option<(&T, &[V])> {
+        self.covering(nb).pop()
+    }
+
+    /// Every entry containing every address of `nb`, shortest prefix first.
+    pub fn covering(&self, nb: &T) -> Vec<(&T, &[V])> {
+        let mut found = Vec::new();
+        let mut node = 0;
+        for depth in 0..=nb.prefix_len() {
+            if let Some((ref prefix, ref values)) = self.nodes[node].entry {
+                found.push((prefix, values.as_slice()));
+            }
+            if depth == nb.prefix_len() {
+                break;
+            }
+            node = self.nodes[node].children[Self::bit(nb, depth)];
+            if node == 0 {
+                break;
+            }
+        }
+        found
+    }
+
+    /// Bit `depth` of the network address of `nb`, counted from the top.
+    fn bit(nb: &T, depth: u8) -> usize {
+        ((nb.first_int() >> (T::BITS - 1 - depth)) & 1) as usize
+    }
+}
+
+impl<T: Aggregateable, V> Default for PrefixTrie<T, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,9 +1,24 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use raggre::{
+    Aggregateable, IpSet4, IpSet6, NetblockParseError, NetblockV4, NetblockV6, OctetPattern,
+    PrefixTrie, PromoteThreshold, Promotion, aggregate_lossy, aggregate_netblocks,
+    aggregate_netblocks_floor, intersect_sets, normalize_netblocks, overlapping_inputs,
+    parse_int_addr, parse_masked_v4, parse_range_v4, parse_range_v6, parse_short_prefix_v4,
+    promote_dense, range_to_prefixes_v4, range_to_prefixes_v6, split_count, split_to_length,
+    subtract_set, wildcard_prefix_count, wildcard_to_prefixes_v4,
+};
+use regex::Regex;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, Read, Write};
 use std::net::{Ipv4Addr, Ipv6Addr};
-use std::str::FromStr;
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
 use unicode_segmentation::UnicodeSegmentation;
 
 // ---------------------------------------------------------------------------
@@ -30,6 +45,101 @@ struct Cli {
     #[arg(long)]
     input_range: bool,
 
+    /// Detect the form of every line: CIDR prefix, bare address, address
+    /// with netmask/wildcard or start-end range, of either family. --stats
+    /// counts each form.
+    #[arg(long)]
+    input_auto: bool,
+
+    /// Accept non-contiguous IPv4 netmasks and Cisco wildcard masks
+    /// (e.g. 10.0.0.0 0.255.0.255), expanding each into the prefixes it
+    /// matches. Without this option such masks are rejected.
+    #[arg(long)]
+    expand_wildcards: bool,
+
+    /// Also accept nmap-style IPv4 patterns: octet ranges and lists
+    /// (10.0.0-3.*, 192.168.1.10-50, 10.0.0.1,3,5), * globs (172.16.*.1)
+    /// and prefixes with trailing zero octets left out (10.1/16)
+    #[arg(long)]
+    input_nmap: bool,
+
+    /// Find every address, prefix and range anywhere in each line, like
+    /// grep -o, for free-form text.  Ports ("1.2.3.4:8080") and brackets
+    /// ("[2001:db8::1]:443") are left out; lines without any are not
+    /// invalid.  --stats counts the matches
+    #[arg(long)]
+    scan: bool,
+
+    /// Filter text instead of aggregating: print the input lines holding
+    /// an address inside the set read from SETFILE.  The address is taken
+    /// with --fields, --regex or the CSV options, or else found anywhere in
+    /// the line as with --scan
+    #[arg(long, value_name = "SETFILE")]
+    grep: Option<String>,
+
+    /// With --grep, print the lines without an address inside the set
+    #[arg(short = 'v', long)]
+    invert_match: bool,
+
+    /// Look up addresses or prefixes in the set read from SETFILE instead
+    /// of aggregating.  The entries are kept as listed; for each query (the
+    /// positional arguments, or else the lines of stdin) the longest entry
+    /// containing it is printed with its comment (see --comment/--dialect).
+    /// SETFILE lines that do not parse are skipped as when aggregating (see
+    /// --rejects), or end the run with status 3 past the --strict/--max-invalid
+    /// limits.  Otherwise exits with status 2 if SETFILE could not be read or
+    /// a query could not be parsed, else 0 if any query matched and 1 if none
+    /// did, like grep
+    #[arg(long, value_name = "SETFILE")]
+    lookup: Option<String>,
+
+    /// With --lookup, print every entry containing the query, shortest first
+    #[arg(long)]
+    lookup_all: bool,
+
+    /// Join the input lines against the CSV table TABLEFILE instead of
+    /// aggregating: each line is printed with the label columns of the
+    /// longest table prefix containing its first matching address (taken
+    /// as with --grep) appended, after the --delimiter, a comma in CSV mode
+    /// or else a space
+    #[arg(long, value_name = "TABLEFILE")]
+    annotate: Option<String>,
+
+    /// 1-based column of the --annotate table holding the prefixes
+    /// [default: 1]
+    #[arg(long, value_name = "N")]
+    table_field_number: Option<usize>,
+
+    /// Header name of the --annotate table column holding the prefixes.
+    /// The first row of the table is then its header
+    #[arg(long, value_name = "NAME")]
+    table_field_name: Option<String>,
+
+    /// Comma-separated 1-based columns of the --annotate table to append
+    /// [default: every column but the prefix one]
+    #[arg(long, value_name = "N,...", value_delimiter = ',')]
+    table_label_numbers: Vec<usize>,
+
+    /// Comma-separated header names of the --annotate table columns to
+    /// append.  The first row of the table is then its header
+    #[arg(long, value_name = "NAME,...", value_delimiter = ',')]
+    table_label_names: Vec<String>,
+
+    /// Text appended for each label column of lines without a match
+    #[arg(long, value_name = "TEXT", default_value = "-")]
+    annotate_placeholder: String,
+
+    /// Also accept addresses written as unsigned integers, decimal or hex
+    /// with a 0x prefix: alone (167772160), with /LEN (0x0a000000/24) or as
+    /// START-END ranges. Values that fit in 32 bits are IPv4 unless -6 is
+    /// given; larger values are IPv6.
+    #[arg(long)]
+    input_int: bool,
+
+    /// Reject input items that expand into more than N prefixes
+    #[arg(long, value_name = "N", default_value_t = 65536)]
+    expand_limit: u64,
+
     /// Output aggregated results as IP ranges instead of CIDR prefixes
     #[arg(long)]
     output_range: bool,
@@ -41,9 +151,21 @@ struct Cli {
 
     /// Output as Cisco-style "network wildcard" (e.g. 10.0.0.0 0.0.0.255).
     /// IPv6 has no wildcard-mask convention, so IPv6 blocks fall back to CIDR.
+    /// 0.0.0.0/0 stays "0.0.0.0/0", since an all-ones mask after whitespace
+    /// reads back as a host netmask (/32).
     #[arg(long)]
     output_wildcard: bool,
 
+    /// Output as ranges of unsigned decimal integers (e.g.
+    /// 167772160-167772415), merging contiguous blocks like --output-range
+    #[arg(long)]
+    output_int_range: bool,
+
+    /// Output as network/prefix with the network as a zero-padded hex
+    /// integer (e.g. 0x0a000000/24)
+    #[arg(long)]
+    output_hex_cidr: bool,
+
     /// Emit results as JSON: IPv4 in "results4", IPv6 in "results6". With
     /// --stats, statistics are placed in a separate "stats" object.
     #[arg(long)]
@@ -58,6 +180,46 @@ struct Cli {
     #[arg(short = 'm', long = "max-length", value_name = "N")]
     max_length: Option<u8>,
 
+    /// Set minimum prefix length of the output (shorter prefixes are split
+    /// into subnets of this length). Lengths above 32 leave IPv4 unchanged.
+    #[arg(long = "min-length", value_name = "N")]
+    min_length: Option<u8>,
+
+    /// Fail instead of printing more than N prefixes per address family
+    /// when splitting for --min-length
+    #[arg(long, value_name = "N", default_value_t = 1_000_000)]
+    split_limit: u64,
+
+    /// Never merge IPv4 siblings into a prefix shorter than N (contained
+    /// blocks are still removed)
+    #[arg(long, value_name = "N")]
+    floor4: Option<u8>,
+
+    /// Never merge IPv6 siblings into a prefix shorter than N (contained
+    /// blocks are still removed)
+    #[arg(long, value_name = "N")]
+    floor6: Option<u8>,
+
+    /// Only sort and drop contained netblocks; do not merge siblings
+    #[arg(long)]
+    no_merge: bool,
+
+    /// Output at most N prefixes per address family: after exact aggregation,
+    /// repeatedly replace neighbouring prefixes by their common supernet,
+    /// choosing the merge that adds the fewest addresses not in the input.
+    /// --stats reports the number of over-covered addresses.
+    #[arg(long, value_name = "N")]
+    max_entries: Option<usize>,
+
+    /// Promote sparse blocks before aggregation: replace the listed parts of
+    /// a /LEN by the whole /LEN when at least THRESHOLD of its units are
+    /// listed. Units are /32 hosts for IPv4 and /64 subnets for IPv6;
+    /// THRESHOLD is a count (16) or a percentage (25%). LEN is IPv4 unless
+    /// -6 is given; write 4/LEN or 6/LEN to choose the family. Repeatable,
+    /// applied longest prefix first.
+    #[arg(long, value_name = "[4/|6/]LEN:THRESHOLD")]
+    promote: Vec<String>,
+
     /// Subtract netblocks listed in FILE from the result
     #[arg(long, value_name = "FILE")]
     exclude: Option<String>,
@@ -74,6 +236,26 @@ struct Cli {
     #[arg(long)]
     stats: bool,
 
+    /// Write every rejected input line to FILE, one per line, as
+    /// "SOURCE:LINE: REASON: ORIGINAL LINE"; "-" writes them to stderr
+    #[arg(long, value_name = "FILE")]
+    rejects: Option<String>,
+
+    /// Fail with exit status 3 if any line of any input (FILE, --exclude,
+    /// --intersect, --diff files) is rejected.  Lines of a family left out
+    /// with -4/-6 are not rejects for this and the limits below
+    #[arg(long)]
+    strict: bool,
+
+    /// Fail with exit status 3 if an input has more than N rejected lines
+    #[arg(long, value_name = "N")]
+    max_invalid: Option<usize>,
+
+    /// Fail with exit status 3 if more than P percent of an input's lines
+    /// are rejected
+    #[arg(long, value_name = "P")]
+    max_invalid_percent: Option<f64>,
+
     /// Field delimiter: a single UTF-8 character or U+XXXX / UXXXX specification.
     /// CESU-8 surrogate code points are rejected.
     /// Must be used together with --fields.
@@ -99,7 +281,66 @@ struct Cli {
     #[arg(long, value_name = "NAME")]
     csv_field_name: Option<String>,
 
-    /// Input file(s) to process (two files required for --diff)
+    /// Aggregate each group of lines separately, grouped by the key in this
+    /// field: a 1-based --delimiter field (negative counts from the end), a
+    /// CSV column number with --csv-field-number or a CSV header name with
+    /// --csv-field-name.  Every line prints as "KEY PREFIX"; --json gives an
+    /// object keyed by group
+    #[arg(long, value_name = "FIELD")]
+    group_by_field: Option<String>,
+
+    /// With --group-by-field, write each group to a file named after its
+    /// key in DIR (created if missing) instead of stdout.  Characters other
+    /// than letters, digits, '-', '_' and '.' become '_'; keys that would
+    /// share a file name are an error
+    #[arg(long, value_name = "DIR")]
+    group_output_dir: Option<String>,
+
+    /// Extract addresses with a regular expression: the capture groups of
+    /// the first match in each line are tried like --fields.  Cannot be
+    /// combined with --delimiter/--fields, the CSV options or --dialect
+    #[arg(long, value_name = "PATTERN")]
+    regex: Option<String>,
+
+    /// Comma-separated capture groups of --regex to try, by number or name
+    /// (default: every group of the pattern, or the whole match if it has
+    /// none)
+    #[arg(long, value_name = "GROUPS")]
+    regex_groups: Option<String>,
+
+    /// Strip comments from MARKER (e.g. '#', ';' or '//') to the end of the
+    /// line before parsing; lines holding only a comment are skipped.
+    /// Repeat or comma-separate for several markers (not with CSV input)
+    #[arg(long, value_name = "MARKER", value_delimiter = ',')]
+    comment: Vec<String>,
+
+    /// Read a blocklist feed format: its comment markers are stripped and
+    /// the network is taken from the column the format keeps it in.
+    /// Cannot be combined with --delimiter/--fields
+    #[arg(long, value_enum, value_name = "NAME")]
+    dialect: Option<Dialect>,
+
+    /// Keep the comment of every input line (requires --comment or
+    /// --dialect) and list the comments of all inputs behind each output
+    /// block: as a trailing comment in text output, as a "comments" array
+    /// with --json
+    #[arg(long)]
+    keep_comments: bool,
+
+    /// With --keep-comments, list at most N comments per output block and
+    /// count the rest
+    #[arg(long, value_name = "N")]
+    max_comments: Option<usize>,
+
+    /// Report the inputs behind every output block: the number of input
+    /// lines merged into it and their file and line numbers, as an
+    /// indented text report or as "inputs" and "sources" with --json
+    #[arg(long)]
+    explain: bool,
+
+    /// Input file(s) to process; all of them are aggregated together.
+    /// "-" reads stdin, directories are read recursively and shell-style
+    /// glob patterns are expanded (two plain files required for --diff)
     #[arg(value_name = "FILE")]
     input: Vec<String>,
 }
@@ -118,1277 +359,2506 @@ impl Cli {
 }
 
 // ---------------------------------------------------------------------------
-// Trait for generic aggregation and set operations
+// Diff helpers
 // ---------------------------------------------------------------------------
 
-trait Aggregateable: Ord + Copy + fmt::Display {
-    /// Return true if `other` is a subnet of `self`.
-    fn contains(&self, other: &Self) -> bool;
-
-    /// Combine sibling netblocks into one, if possible.
-    fn aggregate(&self, other: &Self) -> Option<Self>;
-
-    /// Split a netblock into its two child halves (prefix_len + 1).
-    /// Returns None if already at maximum prefix length.
-    fn split_halves(&self) -> Option<(Self, Self)>;
-
-    /// Format just the first address of this prefix.
-    fn display_start(&self) -> String;
-
-    /// Format just the last address of this prefix.
-    fn display_end(&self) -> String;
-
-    /// Format as network/netmask (family-specific mask representation).
-    fn display_netmask(&self) -> String;
-
-    /// Format as a Cisco-style "network wildcard" pair (IPv4); IPv6
-    /// implementations fall back to CIDR since wildcard masks are IPv4-only.
-    fn display_wildcard(&self) -> String;
-
-    /// Return true if the last address of self is immediately followed
-    /// by the first address of `next` (i.e., they form a contiguous range).
-    fn is_contiguous_with(&self, next: &Self) -> bool;
-
-    /// Number of addresses covered by this prefix, as an exact u128.
-    /// Returns None only for the whole IPv6 space (/0), whose count is 2^128
-    /// and therefore one larger than u128::MAX.
-    fn address_count(&self) -> Option<u128>;
+/// Compare two sorted, aggregated lists and write differences to `out`.
+/// Lines only in `old` are prefixed with "- ", lines only in `new` with "+ ".
+/// Each emitted netblock is rendered using `fmt`.
+fn diff_sorted<T: Aggregateable>(old: &[T], new: &[T], fmt: OutputFormat, out: &mut impl Write) {
+    let mut i = 0;
+    let mut j = 0;
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if old[i] < new[j] {
+            let _ = writeln!(out, "- {}", format_block(&old[i], fmt));
+            i += 1;
+        } else {
+            let _ = writeln!(out, "+ {}", format_block(&new[j], fmt));
+            j += 1;
+        }
+    }
+    while i < old.len() {
+        let _ = writeln!(out, "- {}", format_block(&old[i], fmt));
+        i += 1;
+    }
+    while j < new.len() {
+        let _ = writeln!(out, "+ {}", format_block(&new[j], fmt));
+        j += 1;
+    }
 }
 
-// ---------------------------------------------------------------------------
-// Custom error for parsing netblocks
-// ---------------------------------------------------------------------------
-
-#[derive(Debug)]
-struct NetblockParseError;
-
-impl fmt::Display for NetblockParseError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "invalid netblock format")
+/// Compare two sorted, aggregated lists and collect the differences.
+/// Returns (removed, added): netblocks only in `old`, and only in `new`.
+fn diff_collect<T: Aggregateable>(old: &[T], new: &[T]) -> (Vec<T>, Vec<T>) {
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    let mut i = 0;
+    let mut j = 0;
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if old[i] < new[j] {
+            removed.push(old[i]);
+            i += 1;
+        } else {
+            added.push(new[j]);
+            j += 1;
+        }
+    }
+    while i < old.len() {
+        removed.push(old[i]);
+        i += 1;
+    }
+    while j < new.len() {
+        added.push(new[j]);
+        j += 1;
     }
+    (removed, added)
 }
 
-impl Error for NetblockParseError {}
-
 // ---------------------------------------------------------------------------
-// NetblockV4
+// Output helpers
 // ---------------------------------------------------------------------------
 
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
-struct NetblockV4 {
-    network: Ipv4Addr,
-    prefix_len: u8,
+/// Selected output rendering for netblocks.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// network/prefix (e.g. 10.0.0.0/8)
+    Cidr,
+    /// start-end ranges, merging contiguous blocks
+    Range,
+    /// network/netmask (e.g. 10.0.0.0/255.0.0.0)
+    Netmask,
+    /// Cisco "network wildcard" (e.g. 10.0.0.0 0.255.255.255)
+    Wildcard,
+    /// start-end ranges of unsigned integers, merging contiguous blocks
+    IntRange,
+    /// hex-network/prefix (e.g. 0x0a000000/8)
+    HexCidr,
 }
 
-impl NetblockV4 {
-    /// Zero out bits beyond `prefix_len`.
-    #[inline]
-    fn new(network: Ipv4Addr, prefix_len: u8) -> Self {
-        let masked = if prefix_len == 0 {
-            0
-        } else {
-            let shift = 32 - prefix_len;
-            (u32::from(network) >> shift) << shift
-        };
-        Self { network: Ipv4Addr::from(masked), prefix_len }
+/// Render a single netblock in the given format.
+/// Range mode renders this block's own span; contiguous merging across blocks
+/// is only performed by `format_blocks`.
+fn format_block<T: Aggregateable>(nb: &T, fmt: OutputFormat) -> String {
+    match fmt {
+        OutputFormat::Cidr => nb.to_string(),
+        OutputFormat::Netmask => nb.display_netmask(),
+        OutputFormat::Wildcard => nb.display_wildcard(),
+        OutputFormat::Range => format!("{}-{}", nb.display_start(), nb.display_end()),
+        OutputFormat::IntRange => format!("{}-{}", nb.first_int(), nb.last_int()),
+        OutputFormat::HexCidr => nb.display_hex_cidr(),
     }
+}
 
-    /// Return true if both have the same prefix_len and differ only
-    /// in the last bit of the prefix (siblings in the address space).
-    #[inline]
-    fn aggregateable_with(&self, other: &NetblockV4) -> bool {
-        if self.prefix_len != other.prefix_len || self.prefix_len == 0 {
-            return false;
-        }
-        let shift = 32 - self.prefix_len;
-        let s = u32::from(self.network) >> shift;
-        let o = u32::from(other.network) >> shift;
-        (s ^ o) == 1 // Ensures they differ by EXACTLY the last bit of the prefix
-    }
+/// Renders one end of a range for the range output formats.
+type RangeEnd<T> = fn(&T) -> String;
 
-    /// Check if a network address is canonical (i.e., all bits beyond prefix are zero)
-    #[inline]
-    fn is_canonical(&self) -> bool {
-        if self.prefix_len == 0 {
-            return true;
-        }
-        let shift = 32 - self.prefix_len;
-        let bits = u32::from(self.network);
-        let masked = (bits >> shift) << shift;
-        masked == bits
-    }
+/// Render aggregated netblocks into one output string per line.
+/// In the range modes, adjacent CIDRs are merged into contiguous ranges.
+fn format_blocks<T: Aggregateable>(blocks: &[T], fmt: OutputFormat) -> Vec<String> {
+    format_runs(blocks, fmt).into_iter().map(|(line, _)| line).collect()
 }
 
-impl Aggregateable for NetblockV4 {
-    /// Return true if `other` is a subnet of `self`.
-    #[inline]
-    fn contains(&self, other: &Self) -> bool {
-        if self.prefix_len > other.prefix_len {
-            return false;
-        }
-        if self.prefix_len == 0 {
-            return true; // /0 contains everything
+/// Like `format_blocks`, also returning the indices of the blocks each
+/// output line covers.
+fn format_runs<T: Aggregateable>(blocks: &[T], fmt: OutputFormat) -> Vec<(String, Range<usize>)> {
+    let (display_start, display_end): (RangeEnd<T>, RangeEnd<T>) = match fmt {
+        OutputFormat::Range => (T::display_start, T::display_end),
+        OutputFormat::IntRange => (|nb| nb.first_int().to_string(), |nb| nb.last_int().to_string()),
+        _ => {
+            return blocks
+                .iter()
+                .enumerate()
+                .map(|(i, nb)| (format_block(nb, fmt), i..i + 1))
+                .collect();
         }
-        let shift = 32 - self.prefix_len;
-        ((u32::from(self.network) ^ u32::from(other.network)) >> shift) == 0
-    }
+    };
 
-    /// Combine sibling netblocks into one with prefix_len - 1.
-    #[inline]
-    fn aggregate(&self, other: &Self) -> Option<Self> {
-        if self.aggregateable_with(other) {
-            Some(Self::new(self.network, self.prefix_len - 1))
-        } else {
-            None
-        }
+    let mut out = Vec::new();
+    if blocks.is_empty() {
+        return out;
     }
-
-    #[inline]
-    fn split_halves(&self) -> Option<(Self, Self)> {
-        if self.prefix_len >= 32 {
-            return None;
+    let mut run_start = 0;
+    for i in 1..=blocks.len() {
+        // Extend the current range while blocks are contiguous
+        if i < blocks.len() && blocks[i - 1].is_contiguous_with(&blocks[i]) {
+            continue;
         }
-        let new_len = self.prefix_len + 1;
-        let left = Self::new(self.network, new_len);
-        let right_bits = u32::from(self.network) | (1u32 << (31 - self.prefix_len));
-        let right = Self::new(Ipv4Addr::from(right_bits), new_len);
-        Some((left, right))
-    }
-
-    fn display_start(&self) -> String {
-        format!("{}", self.network)
-    }
-
-    fn display_end(&self) -> String {
-        let start = u32::from(self.network);
-        let end = if self.prefix_len == 0 {
-            u32::MAX
-        } else {
-            start | ((1u32 << (32 - self.prefix_len)) - 1)
-        };
-        format!("{}", Ipv4Addr::from(end))
+        // Emit the completed range and start a new one
+        let line = format!("{}-{}", display_start(&blocks[run_start]), display_end(&blocks[i - 1]));
+        out.push((line, run_start..i));
+        run_start = i;
     }
+    out
+}
 
-    fn display_netmask(&self) -> String {
-        // prefix_len == 0 must be special-cased: `u32::MAX << 32` would overflow.
-        let mask = if self.prefix_len == 0 { 0 } else { u32::MAX << (32 - self.prefix_len) };
-        format!("{}/{}", self.network, Ipv4Addr::from(mask))
+/// Write aggregated netblocks to `out` in the selected format.
+fn write_netblocks<T: Aggregateable>(blocks: &[T], fmt: OutputFormat, out: &mut impl Write) {
+    for line in format_blocks(blocks, fmt) {
+        let _ = writeln!(out, "{}", line);
     }
+}
 
-    fn display_wildcard(&self) -> String {
-        let mask = if self.prefix_len == 0 { 0 } else { u32::MAX << (32 - self.prefix_len) };
-        let wildcard = !mask;
-        format!("{} {}", self.network, Ipv4Addr::from(wildcard))
-    }
+/// Suffix for the --stats address count when --max-entries was applied.
+fn overcovered_note(overcovered: Option<u128>) -> String {
+    overcovered.map_or(String::new(), |n| format!(", {} over-covered", n))
+}
 
-    fn is_contiguous_with(&self, next: &Self) -> bool {
-        let start = u32::from(self.network);
-        let end = if self.prefix_len == 0 {
-            u32::MAX
-        } else {
-            start | ((1u32 << (32 - self.prefix_len)) - 1)
-        };
-        end < u32::MAX && end + 1 == u32::from(next.network)
-    }
+/// Sum address counts across all netblocks, returning an exact decimal string.
+/// Counts are accumulated in u128; the only total that does not fit is the
+/// whole IPv6 space (2^128 = u128::MAX + 1), which is reported as such.
+fn total_addresses_string<T: Aggregateable>(blocks: &[T]) -> String {
+    // 2^128 in decimal (u128::MAX + 1); the single value that overflows u128.
+    const FULL_IPV6_SPACE: &str = "340282366920938463463374607431768211456";
 
-    #[inline]
-    fn address_count(&self) -> Option<u128> {
-        // IPv4 maxes out at 2^32 addresses, which always fits in u128.
-        Some(1u128 << (32 - self.prefix_len))
+    let mut sum: u128 = 0;
+    for b in blocks {
+        match b.address_count() {
+            // None marks the IPv6 /0 block, whose count is exactly 2^128.
+            None => return FULL_IPV6_SPACE.to_string(),
+            Some(c) => match sum.checked_add(c) {
+                Some(s) => sum = s,
+                // Non-overlapping blocks total at most 2^128; overflowing u128
+                // therefore means the sum is exactly 2^128.
+                None => return FULL_IPV6_SPACE.to_string(),
+            },
+        }
     }
+    sum.to_string()
 }
 
-impl FromStr for NetblockV4 {
-    type Err = NetblockParseError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Some((ip_str, prefix_str)) = s.split_once('/') {
-            let ip = Ipv4Addr::from_str(ip_str).map_err(|_| NetblockParseError)?;
-            let prefix = prefix_str.parse::<u8>().map_err(|_| NetblockParseError)?;
+// ---------------------------------------------------------------------------
+// JSON output (dependency-free; results are arrays of plain strings)
+// ---------------------------------------------------------------------------
 
-            if prefix <= 32 {
-                return Ok(Self { network: ip, prefix_len: prefix });
-            }
-        } else {
-            // No /prefix => assume /32
-            if let Ok(ip) = Ipv4Addr::from_str(s) {
-                return Ok(Self { network: ip, prefix_len: 32 });
-            }
+/// Escape a string for safe inclusion inside a JSON string literal.
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
         }
-        Err(NetblockParseError)
     }
+    out
 }
 
-impl fmt::Display for NetblockV4 {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}/{}", self.network, self.prefix_len)
+/// `"key": [ ... ]` member with each element on its own line, indented.
+fn json_array_member(key: &str, items: &[String], indent: usize) -> String {
+    let pad = "  ".repeat(indent);
+    if items.is_empty() {
+        return format!("{}\"{}\": []", pad, json_escape(key));
     }
+    let ipad = "  ".repeat(indent + 1);
+    let body = items
+        .iter()
+        .map(|it| format!("{}\"{}\"", ipad, json_escape(it)))
+        .collect::<Vec<_>>()
+        .join(",\n");
+    format!("{}\"{}\": [\n{}\n{}]", pad, json_escape(key), body, pad)
 }
 
-/// Convert an arbitrary IPv4 range [start, end] into the minimal set of CIDR prefixes.
-fn range_to_prefixes_v4(start: u32, end: u32) -> Vec<NetblockV4> {
-    let mut prefixes = Vec::new();
-    let mut cur = start;
-
-    while cur <= end {
-        // Find how many trailing zero bits `cur` has — this limits alignment
-        let trailing = if cur == 0 { 32 } else { cur.trailing_zeros() };
-
-        // Find the largest block size (power of 2) that fits within [cur, end]
-        let max_size_bits = if end - cur == u32::MAX { 32 } else { (end - cur + 1).ilog2() };
+/// `"key": <number>` member.
+fn json_num_member(key: &str, val: u64, indent: usize) -> String {
+    format!("{}\"{}\": {}", "  ".repeat(indent), json_escape(key), val)
+}
 
-        let bits = std::cmp::min(trailing, max_size_bits);
-        let prefix_len = 32 - bits as u8;
+/// `"key": [n, ...]` member with the numbers on one line.
+fn json_num_array_member(key: &str, vals: &[u64], indent: usize) -> String {
+    let body = vals.iter().map(u64::to_string).collect::<Vec<_>>().join(", ");
+    format!("{}\"{}\": [{}]", "  ".repeat(indent), json_escape(key), body)
+}
 
-        prefixes.push(NetblockV4::new(Ipv4Addr::from(cur), prefix_len));
+/// `"key": "<string>"` member.
+fn json_str_member(key: &str, val: &str, indent: usize) -> String {
+    format!("{}\"{}\": \"{}\"", "  ".repeat(indent), json_escape(key), json_escape(val))
+}
 
-        // Advance past this block
-        let block_size: u64 = 1u64 << bits;
-        let next = cur as u64 + block_size;
-        if next > u32::MAX as u64 {
-            break; // We've covered through 255.255.255.255
-        }
-        cur = next as u32;
+/// `"key": { ... }` member from already-rendered inner members.
+fn json_object_member(key: &str, inner: &[String], indent: usize) -> String {
+    let pad = "  ".repeat(indent);
+    if inner.is_empty() {
+        return format!("{}\"{}\": {{}}", pad, json_escape(key));
     }
-
-    prefixes
+    format!("{}\"{}\": {{\n{}\n{}}}", pad, json_escape(key), inner.join(",\n"), pad)
 }
 
-/// Parse an IPv4 range line of the form "A.B.C.D-E.F.G.H"
-fn parse_range_v4(s: &str) -> Option<(u32, u32)> {
-    let (start_str, end_str) = s.split_once('-')?;
-    let start = Ipv4Addr::from_str(start_str.trim()).ok()?;
-    let end = Ipv4Addr::from_str(end_str.trim()).ok()?;
-    let start_u32 = u32::from(start);
-    let end_u32 = u32::from(end);
-    if start_u32 > end_u32 {
-        return None;
+/// `"key": [ {...}, ... ]` member; each object is given as its already-rendered
+/// inner members, which must be indented at `indent + 2`.
+fn json_object_array_member(key: &str, objects: &[Vec<String>], indent: usize) -> String {
+    let pad = "  ".repeat(indent);
+    if objects.is_empty() {
+        return format!("{}\"{}\": []", pad, json_escape(key));
     }
-    Some((start_u32, end_u32))
+    let ipad = "  ".repeat(indent + 1);
+    let body = objects
+        .iter()
+        .map(|inner| format!("{}{{\n{}\n{}}}", ipad, inner.join(",\n"), ipad))
+        .collect::<Vec<_>>()
+        .join(",\n");
+    format!("{}\"{}\": [\n{}\n{}]", pad, json_escape(key), body, pad)
 }
 
-// ---------------------------------------------------------------------------
-// NetblockV6
-// ---------------------------------------------------------------------------
+/// Wrap top-level members into a complete JSON object document.
+fn json_document(members: &[String]) -> String {
+    if members.is_empty() {
+        return "{}\n".to_string();
+    }
+    format!("{{\n{}\n}}\n", members.join(",\n"))
+}
 
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
-struct NetblockV6 {
-    network: Ipv6Addr,
-    prefix_len: u8,
+/// Build the per-family stats sub-object (input/aggregated/addresses).
+/// Address counts are emitted as strings because IPv6 totals exceed the range
+/// of a JSON-safe integer.
+/// `extra` holds optional members already rendered at indent 3.
+fn json_family_stats(
+    key: &str,
+    input: usize,
+    aggregated: usize,
+    addresses: &str,
+    extra: Vec<String>,
+) -> String {
+    let mut inner = vec![
+        json_num_member("input", input as u64, 3),
+        json_num_member("aggregated", aggregated as u64, 3),
+        json_str_member("addresses", addresses, 3),
+    ];
+    inner.extend(extra);
+    json_object_member(key, &inner, 2)
 }
 
-impl NetblockV6 {
-    /// Create a new `NetblockV6`, zeroing out bits beyond `prefix_len`.
-    #[inline]
-    fn new(network: Ipv6Addr, prefix_len: u8) -> Self {
-        // Shift-based masking
-        let masked = if prefix_len == 0 {
-            0
-        } else {
-            let shift = 128 - prefix_len;
-            let bits = u128::from(network);
-            (bits >> shift) << shift
-        };
-        Self { network: Ipv6Addr::from(masked), prefix_len }
-    }
+// ---------------------------------------------------------------------------
+// Delimiter/field extraction (cut-like UTF-8 aware processing)
+// ---------------------------------------------------------------------------
 
-    /// True if both have the same prefix_len and differ only in the last bit of that prefix.
-    #[inline]
-    fn aggregateable_with(&self, other: &NetblockV6) -> bool {
-        // /0 cannot be aggregated further, and prefixes must match
-        if self.prefix_len != other.prefix_len || self.prefix_len == 0 {
-            return false;
-        }
-        let shift = 128 - self.prefix_len;
-        let s = u128::from(self.network) >> shift;
-        let o = u128::from(other.network) >> shift;
-        // XORing them should result in exactly 1 if they are perfect siblings
-        (s ^ o) == 1
-    }
+/// Options for delimiter-based field extraction (cut-like mode).
+#[derive(Clone)]
+struct FieldOptions {
+    delimiter: char,
+    fields: Vec<i32>,
+}
 
-    /// Check if an IPv6 address is canonical for its prefix (all bits beyond prefix are zero)
-    #[inline]
-    fn is_canonical(&self) -> bool {
-        if self.prefix_len == 0 {
-            return true;
+/// Parse a delimiter specification into a single Unicode scalar value.
+/// Accepts:
+///   - A single UTF-8 character (e.g. ":" or "→")
+///   - UXXXX or U+XXXX hex notation (e.g. "U003A" or "U+003A" for ':')
+///     Rejects CESU-8 surrogate code points (U+D800..U+DFFF).
+fn parse_delimiter(s: &str) -> Result<char, String> {
+    // Try U+XXXX or UXXXX format
+    let hex_str = if let Some(stripped) = s.strip_prefix("U+") {
+        Some(stripped)
+    } else {
+        s.strip_prefix('U').filter(|&stripped| {
+            !stripped.is_empty() && stripped.chars().all(|c| c.is_ascii_hexdigit())
+        })
+    };
+
+    if let Some(hex) = hex_str {
+        if hex.is_empty() {
+            return Err("empty code point after 'U' prefix".to_string());
+        }
+        let code_point =
+            u32::from_str_radix(hex, 16).map_err(|_| format!("invalid Unicode escape: {}", s))?;
+        // Reject CESU-8 surrogates
+        if (0xD800..=0xDFFF).contains(&code_point) {
+            return Err(format!("CESU-8 surrogate code point U+{:04X} is not allowed", code_point));
+        }
+        char::from_u32(code_point)
+            .ok_or_else(|| format!("invalid Unicode code point: U+{:04X}", code_point))
+    } else {
+        // Must be exactly one Unicode scalar value (one grapheme cluster of one code point)
+        let graphemes: Vec<&str> = s.graphemes(true).collect();
+        if graphemes.len() != 1 {
+            return Err(format!(
+                "delimiter must be a single character, got {} grapheme cluster(s): {:?}",
+                graphemes.len(),
+                s
+            ));
+        }
+        let mut chars = graphemes[0].chars();
+        let ch = chars.next().ok_or_else(|| "empty delimiter".to_string())?;
+        if chars.next().is_some() {
+            return Err(format!(
+                "delimiter must be a single code point, \
+                 {:?} is a multi-code-point grapheme cluster",
+                graphemes[0]
+            ));
         }
-        let shift = 128 - self.prefix_len;
-        let bits = u128::from(self.network);
-        let masked = (bits >> shift) << shift;
-        masked == bits
+        Ok(ch)
     }
 }
 
-impl Aggregateable for NetblockV6 {
-    /// True if `other` is fully contained in `self`.
-    #[inline]
-    fn contains(&self, other: &Self) -> bool {
-        if self.prefix_len > other.prefix_len {
-            return false;
+/// Parse a comma-separated list of 1-based field numbers.
+/// Positive numbers index from the start (1 = first).
+/// Negative numbers index from the end (-1 = last).
+/// Zero is not a valid field number.
+fn parse_field_spec(s: &str) -> Result<Vec<i32>, String> {
+    let mut fields = Vec::new();
+    for part in s.split(',') {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
         }
-        if self.prefix_len == 0 {
-            return true; // /0 contains all of IPv6 space
+        let n: i32 = part.parse().map_err(|_| format!("invalid field number: {:?}", part))?;
+        if n == 0 {
+            return Err("field numbers are 1-based, 0 is not valid".to_string());
         }
-        let shift = 128 - self.prefix_len;
-        ((u128::from(self.network) ^ u128::from(other.network)) >> shift) == 0
+        fields.push(n);
     }
-
-    /// Merge two siblings into a single netblock with prefix_len - 1, if possible.
-    #[inline]
-    fn aggregate(&self, other: &Self) -> Option<Self> {
-        if self.aggregateable_with(other) {
-            // We create the bigger block by using `self.network` and prefix_len - 1
-            Some(Self::new(self.network, self.prefix_len - 1))
-        } else {
-            None
-        }
+    if fields.is_empty() {
+        return Err("no fields specified".to_string());
     }
+    Ok(fields)
+}
 
-    #[inline]
-    fn split_halves(&self) -> Option<(Self, Self)> {
-        if self.prefix_len >= 128 {
+/// Extract a single field from a line split by delimiter.
+/// Positive field numbers are 1-based from the start.
+/// Negative field numbers count from the end (-1 = last field).
+fn extract_field<'a>(parts: &[&'a str], field: i32) -> Option<&'a str> {
+    let index = if field > 0 {
+        (field - 1) as usize
+    } else {
+        // Negative: count from end (-1 = last element)
+        let abs_field = (-field) as usize;
+        if abs_field > parts.len() {
             return None;
         }
-        let new_len = self.prefix_len + 1;
-        let left = Self::new(self.network, new_len);
-        let right_bits = u128::from(self.network) | (1u128 << (127 - self.prefix_len));
-        let right = Self::new(Ipv6Addr::from(right_bits), new_len);
-        Some((left, right))
-    }
+        parts.len() - abs_field
+    };
+    parts.get(index).copied()
+}
 
-    fn display_start(&self) -> String {
-        format!("{}", self.network)
-    }
+// ---------------------------------------------------------------------------
+// Regex extraction (--regex)
+// ---------------------------------------------------------------------------
+
+/// Options for regex-based extraction.
+#[derive(Clone)]
+struct RegexOptions {
+    regex: Regex,
+    /// Capture group indices to try, in order
+    groups: Vec<usize>,
+}
 
-    fn display_end(&self) -> String {
-        let start = u128::from(self.network);
-        let end = if self.prefix_len == 0 {
-            u128::MAX
+/// Resolve a --regex-groups list of group numbers and names into capture
+/// group indices.  Without a list every group is used, or group 0 (the
+/// whole match) when the pattern has none.
+fn parse_regex_groups(regex: &Regex, spec: Option<&str>) -> Result<Vec<usize>, String> {
+    let Some(spec) = spec else {
+        return Ok(if regex.captures_len() > 1 {
+            (1..regex.captures_len()).collect()
         } else {
-            start | ((1u128 << (128 - self.prefix_len)) - 1)
+            vec![0]
+        });
+    };
+    let mut groups = Vec::new();
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let group = match part.parse::<usize>() {
+            Ok(n) if n < regex.captures_len() => n,
+            Ok(n) => {
+                return Err(format!(
+                    "group {} does not exist, the pattern has {} capture group(s)",
+                    n,
+                    regex.captures_len() - 1
+                ));
+            }
+            Err(_) => regex
+                .capture_names()
+                .position(|name| name == Some(part))
+                .ok_or_else(|| format!("no capture group named {:?}", part))?,
         };
-        format!("{}", Ipv6Addr::from(end))
+        groups.push(group);
     }
-
-    fn display_netmask(&self) -> String {
-        // prefix_len == 0 must be special-cased: `u128::MAX << 128` would overflow.
-        let mask = if self.prefix_len == 0 { 0 } else { u128::MAX << (128 - self.prefix_len) };
-        format!("{}/{}", self.network, Ipv6Addr::from(mask))
+    if groups.is_empty() {
+        return Err("no groups specified".to_string());
     }
+    Ok(groups)
+}
 
-    fn display_wildcard(&self) -> String {
-        // IPv6 has no Cisco-style wildcard-mask convention; fall back to CIDR.
-        format!("{}/{}", self.network, self.prefix_len)
-    }
+// ---------------------------------------------------------------------------
+// CSV field extraction
+// ---------------------------------------------------------------------------
 
-    fn is_contiguous_with(&self, next: &Self) -> bool {
-        let start = u128::from(self.network);
-        let end = if self.prefix_len == 0 {
-            u128::MAX
-        } else {
-            start | ((1u128 << (128 - self.prefix_len)) - 1)
-        };
-        end < u128::MAX && end + 1 == u128::from(next.network)
-    }
+/// Specifies which column to extract from CSV input.
+#[derive(Clone)]
+enum CsvOptions {
+    /// Column by 0-based index (user provides 1-based, converted before storing).
+    ByNumber(usize),
+    /// Column by header name (first row is the header).
+    ByName(String),
+}
 
-    #[inline]
-    fn address_count(&self) -> Option<u128> {
-        // A /0 covers 2^128 addresses, which is one more than u128::MAX,
-        // so it cannot be represented; the caller treats None as 2^128.
-        if self.prefix_len == 0 { None } else { Some(1u128 << (128 - self.prefix_len)) }
-    }
+/// Where --group-by-field takes the group key of a line from.
+#[derive(Clone)]
+enum GroupField {
+    /// 1-based field after splitting by --delimiter; negative counts from the end
+    Field(i32),
+    /// CSV column
+    Csv(CsvOptions),
 }
 
-impl FromStr for NetblockV6 {
-    type Err = NetblockParseError;
+/// Index of the column named `name` in a CSV header row.
+fn csv_header_index(headers: &csv::StringRecord, name: &str) -> Result<usize, String> {
+    headers.iter().position(|h| h.trim() == name.trim()).ok_or_else(|| {
+        format!(
+            "CSV header {:?} not found (available: {})",
+            name,
+            headers.iter().map(|h| format!("{:?}", h)).collect::<Vec<_>>().join(", ")
+        )
+    })
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Some((ip_str, prefix_str)) = s.split_once('/') {
-            let ip = Ipv6Addr::from_str(ip_str).map_err(|_| NetblockParseError)?;
-            let prefix = prefix_str.parse::<u8>().map_err(|_| NetblockParseError)?;
-            if prefix <= 128 {
-                return Ok(Self { network: ip, prefix_len: prefix });
-            }
-        } else {
-            // No prefix => assume /128
-            if let Ok(ip) = Ipv6Addr::from_str(s) {
-                return Ok(Self { network: ip, prefix_len: 128 });
-            }
-        }
-        Err(NetblockParseError)
-    }
+/// Parse a single line as a CSV record.
+fn csv_record(line: &str) -> Option<csv::StringRecord> {
+    let mut rdr = csv::ReaderBuilder::new().has_headers(false).from_reader(line.as_bytes());
+    rdr.records().next()?.ok()
 }
 
-impl fmt::Display for NetblockV6 {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}/{}", self.network, self.prefix_len)
-    }
+/// Bytes read through a `RawTail`, starting at byte offset `start`.
+#[derive(Default)]
+struct RawBytes {
+    start: u64,
+    bytes: Vec<u8>,
 }
 
-/// Convert an arbitrary IPv6 range [start, end] into the minimal set of CIDR prefixes.
-fn range_to_prefixes_v6(start: u128, end: u128) -> Vec<NetblockV6> {
-    let mut prefixes = Vec::new();
-    let mut cur = start;
+impl RawBytes {
+    /// The input between byte offsets `from` and `to`, without the line ending.
+    fn text(&self, from: u64, to: u64) -> String {
+        let from = (from.saturating_sub(self.start) as usize).min(self.bytes.len());
+        let to = (to.saturating_sub(self.start) as usize).clamp(from, self.bytes.len());
+        String::from_utf8_lossy(&self.bytes[from..to]).trim_end_matches(['\r', '\n']).to_string()
+    }
 
-    while cur <= end {
-        // Find how many trailing zero bits `cur` has — this limits alignment
-        let trailing = if cur == 0 { 128 } else { cur.trailing_zeros() };
+    /// Forget the input before byte offset `to`.
+    fn drain_to(&mut self, to: u64) {
+        let n = (to.saturating_sub(self.start) as usize).min(self.bytes.len());
+        self.bytes.drain(..n);
+        self.start += n as u64;
+    }
+}
 
-        // Find the largest block size (power of 2) that fits within [cur, end]
-        let max_size_bits = if end - cur == u128::MAX { 128 } else { (end - cur + 1).ilog2() };
+/// Reader keeping what the CSV reader has consumed, so that rejected
+/// records can be reported as they were written.
+struct RawTail {
+    inner: Box<dyn io::Read>,
+    raw: Rc<RefCell<RawBytes>>,
+}
 
-        let bits = std::cmp::min(trailing, max_size_bits);
-        let prefix_len = 128 - bits as u8;
+/// Read the next CSV record into `record`, with its raw text from `raw`
+/// (the bytes kept by the `RawTail` that `rdr` reads from).
+fn read_raw_record(
+    rdr: &mut csv::Reader<RawTail>,
+    raw: &RefCell<RawBytes>,
+    record: &mut csv::StringRecord,
+) -> (csv::Result<bool>, String) {
+    let row_result = rdr.read_record(record);
+    let record_start = match row_result {
+        Err(ref e) => e.position().map_or(0, csv::Position::byte),
+        Ok(_) => record.position().map_or(0, csv::Position::byte),
+    };
+    let end = rdr.position().byte();
+    let text = raw.borrow().text(record_start, end);
+    raw.borrow_mut().drain_to(end);
+    (row_result, text)
+}
 
-        prefixes.push(NetblockV6::new(Ipv6Addr::from(cur), prefix_len));
+impl io::Read for RawTail {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.raw.borrow_mut().bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
 
-        // A /0 covers the entire space in one block; computing block_size below
-        // as `1u128 << 128` would overflow the shift, so stop once it is emitted.
-        if bits >= 128 {
-            break;
+/// Read netblocks from a CSV source using the `csv` crate.
+/// Extracts the specified column from each row and feeds it through `process_line`.
+fn read_netblocks_csv(
+    reader: Box<dyn io::Read>,
+    source: &str,
+    csv_opts: &CsvOptions,
+    opts: &ReadOptions,
+    rejects: &mut RejectSink,
+) -> Result<ParseResult, Box<dyn Error>> {
+    let mut result = ParseResult::default();
+    let source_name: Rc<str> = Rc::from(source);
+
+    let has_headers = matches!(csv_opts, CsvOptions::ByName(_));
+    let raw = Rc::new(RefCell::new(RawBytes::default()));
+    let reader = RawTail { inner: reader, raw: Rc::clone(&raw) };
+    let mut rdr = csv::ReaderBuilder::new().has_headers(has_headers).from_reader(reader);
+
+    let col_index = match csv_opts {
+        CsvOptions::ByNumber(idx) => *idx,
+        // Find the column index from the header row
+        CsvOptions::ByName(name) => csv_header_index(rdr.headers()?, name)?,
+    };
+    let group_index = match opts.group_by {
+        Some(GroupField::Csv(CsvOptions::ByNumber(idx))) => Some(idx),
+        Some(GroupField::Csv(CsvOptions::ByName(ref name))) => {
+            Some(csv_header_index(rdr.headers()?, name)?)
         }
+        _ => None,
+    };
 
-        // Advance past this block
-        let block_size: u128 = 1u128 << bits;
-        match cur.checked_add(block_size) {
-            Some(next) => cur = next,
-            None => break, // We've covered through ffff:...:ffff
+    let mut record = csv::StringRecord::new();
+    loop {
+        let (row_result, text) = read_raw_record(&mut rdr, &raw, &mut record);
+        match row_result {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                // Read errors (e.g. corrupt compressed data) end the input
+                if matches!(e.kind(), csv::ErrorKind::Io(_)) {
+                    return Err(format!("{}: {}", source, e).into());
+                }
+                let line_no = e.position().map_or(0, |p| p.line());
+                // Otherwise csv::Error is a UTF-8 error or a parse error
+                if matches!(e.kind(), csv::ErrorKind::Utf8 { .. }) {
+                    result.utf8_invalid_lines += 1;
+                    result.reject(rejects, source, line_no, &NetblockParseError::BadUtf8, &text)?;
+                } else {
+                    // A malformed record is a line like any other invalid one
+                    result.total_lines += 1;
+                    result.invalid_lines += 1;
+                    result.reject(rejects, source, line_no, &e, &text)?;
+                }
+                continue;
+            }
+        }
+        result.total_lines += 1;
+
+        let field_val = record.get(col_index).map_or("", str::trim);
+        let line_no = record.position().map_or(0, |p| p.line());
+        let group = group_index.map(|idx| record.get(idx).map_or("", str::trim));
+        if group == Some("") {
+            result.invalid_lines += 1;
+            result.reject(rejects, source, line_no, &"missing group key", &text)?;
+            continue;
+        }
+        let (v4_start, v6_start) = (result.v4.len(), result.v6.len());
+        let parsed = process_line(field_val, opts, &mut result);
+        if let Some(key) = group {
+            result.record_group(v4_start, v6_start, &Rc::from(key));
+        }
+        if let Err(e) = parsed {
+            result.invalid_lines += 1;
+            result.reject_parse(rejects, source, line_no, &e, &text)?;
+        } else if opts.explain {
+            let origin = Origin { source: Rc::clone(&source_name), line: line_no, comment: None };
+            result.record_origin(v4_start, v6_start, &origin);
         }
     }
 
-    prefixes
-}
-
-/// Parse an IPv6 range line of the form "addr1-addr2"
-fn parse_range_v6(s: &str) -> Option<(u128, u128)> {
-    let (start_str, end_str) = s.split_once('-')?;
-    let start = Ipv6Addr::from_str(start_str.trim()).ok()?;
-    let end = Ipv6Addr::from_str(end_str.trim()).ok()?;
-    let start_u128 = u128::from(start);
-    let end_u128 = u128::from(end);
-    if start_u128 > end_u128 {
-        return None;
+    // Apply max-length clamping if requested
+    if let Some(max_len) = opts.max_length {
+        apply_max_length_v4(&mut result.v4, max_len);
+        apply_max_length_v6(&mut result.v6, max_len);
     }
-    Some((start_u128, end_u128))
+
+    Ok(result)
 }
 
 // ---------------------------------------------------------------------------
-// Generic aggregation — single implementation for both address families
+// Comments and feed dialects
 // ---------------------------------------------------------------------------
 
-/// Sort and remove contained (duplicate/subset) netblocks without merging
-/// siblings.  Used by --diff so that individual prefixes are compared as-is
-/// rather than being collapsed into larger blocks first.
-fn normalize_netblocks<T: Aggregateable>(mut netblocks: Vec<T>) -> Vec<T> {
-    if netblocks.len() <= 1 {
-        return netblocks;
-    }
-
-    netblocks.sort_unstable();
+/// Blocklist feed formats understood by --dialect.
+#[derive(Clone, Copy, ValueEnum)]
+enum Dialect {
+    /// Spamhaus DROP lists: "1.2.3.0/24 ; SBL12345", ';' comment lines
+    Spamhaus,
+    /// FireHOL netsets: one network per line, '#' comments
+    Firehol,
+    /// hosts files: "0.0.0.0 example.com", '#' comments
+    Hosts,
+}
 
-    let mut deduped = Vec::with_capacity(netblocks.len());
-    let mut prev = netblocks[0];
+impl Dialect {
+    /// Comment markers used by the format.
+    fn comment_markers(self) -> &'static [&'static str] {
+        match self {
+            Dialect::Spamhaus => &[";"],
+            Dialect::Firehol | Dialect::Hosts => &["#"],
+        }
+    }
 
-    for &current in &netblocks[1..] {
-        if !prev.contains(&current) {
-            deduped.push(prev);
-            prev = current;
+    /// 0-based whitespace-separated column holding the network, or None
+    /// when the whole line is the network.
+    fn column(self) -> Option<usize> {
+        match self {
+            Dialect::Spamhaus | Dialect::Hosts => Some(0),
+            Dialect::Firehol => None,
         }
     }
-    deduped.push(prev);
-    deduped
 }
 
-/// Merge netblocks until no more merges are possible.
-fn aggregate_netblocks<T: Aggregateable>(mut netblocks: Vec<T>) -> Vec<T> {
-    if netblocks.len() <= 1 {
-        return netblocks;
+/// Split `line` at the earliest comment marker into the text before the
+/// marker and the comment after it.
+fn split_comment<'a>(line: &'a str, markers: &[String]) -> (&'a str, Option<&'a str>) {
+    let found = markers.iter().filter_map(|m| line.find(m.as_str()).map(|at| (at, m.len()))).min();
+    match found {
+        Some((at, len)) => (&line[..at], Some(&line[at + len..])),
+        None => (line, None),
     }
+}
 
-    // Sort once at the start
-    netblocks.sort_unstable();
-
-    // First pass: remove contained netblocks
-    let mut deduped = Vec::with_capacity(netblocks.len());
-    let mut prev = netblocks[0];
-
-    for &current in &netblocks[1..] {
-        if !prev.contains(&current) {
-            deduped.push(prev);
-            prev = current;
-        }
-        // If contained, skip current and keep prev
-    }
-    deduped.push(prev);
+/// Reduce a raw input line to the text to parse: comments are stripped and,
+/// when a dialect names a column, only that column is kept.  Returns None
+/// for lines left empty.
+fn line_payload<'a>(line: &'a str, opts: &ReadOptions) -> Option<&'a str> {
+    let line = split_comment(line, &opts.comment_markers).0.trim();
+    let line = match opts.column {
+        Some(column) => line.split_whitespace().nth(column)?,
+        None => line,
+    };
+    (!line.is_empty()).then_some(line)
+}
 
-    // Second pass: iteratively aggregate siblings
-    loop {
-        let mut result = Vec::with_capacity(deduped.len());
-        let mut changed = false;
-        let mut i = 0;
+// ---------------------------------------------------------------------------
+// Input origins (--keep-comments, --explain)
+// ---------------------------------------------------------------------------
 
-        while i < deduped.len() {
-            let current = deduped[i];
+/// Where an input netblock was read from.
+#[derive(Clone)]
+struct Origin {
+    source: Rc<str>,
+    line: u64,
+    /// Comment of the line (--keep-comments)
+    comment: Option<Rc<str>>,
+}
 
-            // Try to aggregate with next element
-            if i + 1 < deduped.len()
-                && let Some(aggregated) = current.aggregate(&deduped[i + 1])
-            {
-                result.push(aggregated);
-                changed = true;
-                i += 2; // Skip both elements
-                continue;
-            }
+/// What to report about the inputs behind each output line.
+struct OriginReport {
+    comments: bool,
+    max_comments: Option<usize>,
+    explain: bool,
+}
 
-            result.push(current);
-            i += 1;
-        }
+/// The comments of the inputs behind one output line.
+struct Comments {
+    /// Distinct comments, in address order of their inputs
+    listed: Vec<Rc<str>>,
+    /// Further distinct comments left out by --max-comments
+    omitted: usize,
+}
 
-        if !changed {
-            return result;
-        }
+/// The input lines behind one output line (--explain).
+struct Provenance {
+    /// Number of distinct input lines
+    inputs: usize,
+    /// Sorted line numbers per source, in order of first appearance
+    sources: Vec<(Rc<str>, Vec<u64>)>,
+}
 
-        // Swap for next iteration - avoids reallocation
-        std::mem::swap(&mut deduped, &mut result);
+/// An output line with the requested report on its inputs.
+struct ReportedLine {
+    line: String,
+    comments: Option<Comments>,
+    provenance: Option<Provenance>,
+}
 
-        // Re-sort only if we made changes
-        deduped.sort_unstable();
-    }
+/// Render `blocks` like `format_blocks`, reporting on the inputs that
+/// overlap every output line.
+fn report_lines<T: Aggregateable>(
+    blocks: &[T],
+    fmt: OutputFormat,
+    origins: &[(T, Origin)],
+    report: &OriginReport,
+) -> Vec<ReportedLine> {
+    let inputs: Vec<T> = origins.iter().map(|(nb, _)| *nb).collect();
+    let overlaps = overlapping_inputs(blocks, &inputs);
+    format_runs(blocks, fmt)
+        .into_iter()
+        .map(|(line, run)| {
+            let behind: Vec<&Origin> =
+                overlaps[run].iter().flatten().map(|&i| &origins[i].1).collect();
+            ReportedLine {
+                line,
+                comments: report.comments.then(|| collect_comments(&behind, report.max_comments)),
+                provenance: report.explain.then(|| collect_provenance(&behind)),
+            }
+        })
+        .collect()
 }
 
-// ---------------------------------------------------------------------------
-// Set operations — generic for both address families
-// ---------------------------------------------------------------------------
+/// Distinct comments of `behind`, keeping at most `max`.
+fn collect_comments(behind: &[&Origin], max: Option<usize>) -> Comments {
+    let mut seen = HashSet::new();
+    let mut listed: Vec<Rc<str>> = behind
+        .iter()
+        .filter_map(|origin| origin.comment.as_ref())
+        .filter(|comment| seen.insert(Rc::clone(comment)))
+        .cloned()
+        .collect();
+    let keep = max.unwrap_or(listed.len()).min(listed.len());
+    let omitted = listed.len() - keep;
+    listed.truncate(keep);
+    Comments { listed, omitted }
+}
 
-/// Remove a single netblock from `block`, returning the remaining fragments.
-/// Recursively splits `block` until the excluded region is carved out.
-fn subtract_single<T: Aggregateable>(block: T, excl: &T) -> Vec<T> {
-    if excl.contains(&block) {
-        // Entire block is excluded
-        return vec![];
-    }
-    if !block.contains(excl) {
-        // No overlap at all
-        return vec![block];
-    }
-    // block contains excl — split into halves and recurse
-    let (left, right) = block.split_halves().expect("block must be larger than excl");
-    let mut result = subtract_single(left, excl);
-    result.extend(subtract_single(right, excl));
-    result
-}
-
-/// Subtract all `excludes` from `blocks`, returning the remaining netblocks.
-fn subtract_set<T: Aggregateable>(mut blocks: Vec<T>, excludes: &[T]) -> Vec<T> {
-    for excl in excludes {
-        let mut remaining = Vec::with_capacity(blocks.len());
-        for &block in &blocks {
-            remaining.extend(subtract_single(block, excl));
-        }
-        blocks = remaining;
-    }
-    // Re-aggregate to merge any newly-adjacent siblings
-    aggregate_netblocks(blocks)
-}
-
-/// Intersect two sets of netblocks, keeping only the overlapping regions.
-fn intersect_sets<T: Aggregateable>(a: &[T], b: &[T]) -> Vec<T> {
-    let mut result = Vec::new();
-    for &x in a {
-        for &y in b {
-            if x.contains(&y) {
-                result.push(y);
-            } else if y.contains(&x) {
-                result.push(x);
-                break; // x is fully covered, no need to check more of b
-            }
+/// Group the lines of `behind` by source.  A line yielding several
+/// netblocks (a range, a pattern) counts once.
+fn collect_provenance(behind: &[&Origin]) -> Provenance {
+    let mut sources: Vec<(Rc<str>, Vec<u64>)> = Vec::new();
+    for origin in behind {
+        match sources.iter_mut().find(|(source, _)| *source == origin.source) {
+            Some((_, lines)) => lines.push(origin.line),
+            None => sources.push((Rc::clone(&origin.source), vec![origin.line])),
         }
     }
-    // Re-aggregate to remove duplicates
-    aggregate_netblocks(result)
+    for (_, lines) in &mut sources {
+        lines.sort_unstable();
+        lines.dedup();
+    }
+    let inputs = sources.iter().map(|(_, lines)| lines.len()).sum();
+    Provenance { inputs, sources }
 }
 
-/// Compare two sorted, aggregated lists and write differences to `out`.
-/// Lines only in `old` are prefixed with "- ", lines only in `new` with "+ ".
-/// Each emitted netblock is rendered using `fmt`.
-fn diff_sorted<T: Aggregateable>(old: &[T], new: &[T], fmt: OutputFormat, out: &mut impl Write) {
+/// Compact sorted line numbers into runs, e.g. "3-5, 9".
+fn line_runs(lines: &[u64]) -> String {
+    let mut runs: Vec<String> = Vec::new();
     let mut i = 0;
-    let mut j = 0;
-    while i < old.len() && j < new.len() {
-        if old[i] == new[j] {
-            i += 1;
-            j += 1;
-        } else if old[i] < new[j] {
-            let _ = writeln!(out, "- {}", format_block(&old[i], fmt));
-            i += 1;
-        } else {
-            let _ = writeln!(out, "+ {}", format_block(&new[j], fmt));
+    while i < lines.len() {
+        let mut j = i;
+        while j + 1 < lines.len() && lines[j + 1] == lines[j] + 1 {
             j += 1;
         }
+        runs.push(if i == j { lines[i].to_string() } else { format!("{}-{}", lines[i], lines[j]) });
+        i = j + 1;
     }
-    while i < old.len() {
-        let _ = writeln!(out, "- {}", format_block(&old[i], fmt));
-        i += 1;
+    runs.join(", ")
+}
+
+/// Write one reported line as text: the block, its input count with
+/// --explain and its comments after `marker`, then with --explain one
+/// indented "source: lines" row per input source.
+fn write_reported_line(reported: &ReportedLine, marker: Option<&str>, out: &mut impl Write) {
+    let mut head = reported.line.clone();
+    if let Some(ref p) = reported.provenance {
+        head += &format!(": {} input{}", p.inputs, if p.inputs == 1 { "" } else { "s" });
     }
-    while j < new.len() {
-        let _ = writeln!(out, "+ {}", format_block(&new[j], fmt));
-        j += 1;
+    if let Some(ref c) = reported.comments
+        && (!c.listed.is_empty() || c.omitted > 0)
+    {
+        let mut text = c.listed.join(", ");
+        if c.omitted > 0 {
+            let sep = if text.is_empty() { "" } else { ", " };
+            text = format!("{}{}+{} more", text, sep, c.omitted);
+        }
+        head = format!("{} {} {}", head, marker.unwrap_or("#"), text);
+    }
+    let _ = writeln!(out, "{}", head);
+    if let Some(ref p) = reported.provenance {
+        for (source, lines) in &p.sources {
+            let _ = writeln!(out, "  {}: {}", source, line_runs(lines));
+        }
     }
 }
 
-/// Compare two sorted, aggregated lists and collect the differences.
-/// Returns (removed, added): netblocks only in `old`, and only in `new`.
-fn diff_collect<T: Aggregateable>(old: &[T], new: &[T]) -> (Vec<T>, Vec<T>) {
-    let mut removed = Vec::new();
-    let mut added = Vec::new();
-    let mut i = 0;
-    let mut j = 0;
-    while i < old.len() && j < new.len() {
-        if old[i] == new[j] {
-            i += 1;
-            j += 1;
-        } else if old[i] < new[j] {
-            removed.push(old[i]);
-            i += 1;
-        } else {
-            added.push(new[j]);
-            j += 1;
+/// JSON members (indent 3) of one reported line.
+fn json_reported_line(reported: &ReportedLine) -> Vec<String> {
+    let mut inner = vec![json_str_member("block", &reported.line, 3)];
+    if let Some(ref c) = reported.comments {
+        let listed: Vec<String> = c.listed.iter().map(|c| c.to_string()).collect();
+        inner.push(json_array_member("comments", &listed, 3));
+        if c.omitted > 0 {
+            inner.push(json_num_member("omitted", c.omitted as u64, 3));
         }
     }
-    while i < old.len() {
-        removed.push(old[i]);
-        i += 1;
-    }
-    while j < new.len() {
-        added.push(new[j]);
-        j += 1;
-    }
-    (removed, added)
+    if let Some(ref p) = reported.provenance {
+        inner.push(json_num_member("inputs", p.inputs as u64, 3));
+        let sources: Vec<Vec<String>> = p
+            .sources
+            .iter()
+            .map(|(source, lines)| {
+                vec![json_str_member("file", source, 5), json_num_array_member("lines", lines, 5)]
+            })
+            .collect();
+        inner.push(json_object_array_member("sources", &sources, 3));
+    }
+    inner
 }
 
 // ---------------------------------------------------------------------------
-// Output helpers
+// Input processing
 // ---------------------------------------------------------------------------
 
-/// Selected output rendering for netblocks.
+/// Parsing options shared by every input source (positional files,
+/// --exclude, --intersect and both --diff files).
+#[derive(Clone)]
+struct ReadOptions {
+    input_mode: InputMode,
+    ignore_invalid: bool,
+    accept_v4: bool,
+    accept_v6: bool,
+    max_length: Option<u8>,
+    expand_wildcards: bool,
+    expand_limit: u64,
+    nmap: bool,
+    int: bool,
+    scan: bool,
+    comment_markers: Vec<String>,
+    column: Option<usize>,
+    keep_comments: bool,
+    explain: bool,
+    fields: Option<FieldOptions>,
+    regex: Option<RegexOptions>,
+    csv: Option<CsvOptions>,
+    group_by: Option<GroupField>,
+}
+
+/// How input lines are interpreted.
 #[derive(Clone, Copy, PartialEq, Eq)]
-enum OutputFormat {
-    /// network/prefix (e.g. 10.0.0.0/8)
+enum InputMode {
+    /// CIDR prefixes and bare addresses (the default)
     Cidr,
-    /// start-end ranges, merging contiguous blocks
+    /// start-end ranges (--input-range)
     Range,
-    /// network/netmask (e.g. 10.0.0.0/255.0.0.0)
-    Netmask,
-    /// Cisco "network wildcard" (e.g. 10.0.0.0 0.255.255.255)
-    Wildcard,
+    /// Any of the above, detected per line (--input-auto)
+    Auto,
 }
 
-/// Render a single netblock in the given format.
-/// Range mode renders this block's own span; contiguous merging across blocks
-/// is only performed by `format_blocks`.
-fn format_block<T: Aggregateable>(nb: &T, fmt: OutputFormat) -> String {
-    match fmt {
-        OutputFormat::Cidr => nb.to_string(),
-        OutputFormat::Netmask => nb.display_netmask(),
-        OutputFormat::Wildcard => nb.display_wildcard(),
-        OutputFormat::Range => format!("{}-{}", nb.display_start(), nb.display_end()),
+/// Syntactic form of an accepted input item.
+#[derive(Clone, Copy)]
+enum InputForm {
+    Cidr,
+    Address,
+    /// Address with a netmask or wildcard mask
+    Mask,
+    Range,
+    /// nmap-style pattern or short prefix
+    Pattern,
+    /// Address, prefix or range written with integers
+    Integer,
+}
+
+/// Accepted input items per form, for --stats.
+#[derive(Default, Clone, Copy)]
+struct FormCounts {
+    cidr: usize,
+    address: usize,
+    mask: usize,
+    range: usize,
+    pattern: usize,
+    integer: usize,
+}
+
+/// Matches found by --scan, for --stats.
+#[derive(Default, Clone, Copy)]
+struct ScanCounts {
+    matches: usize,
+    /// Lines with at least one match
+    lines: usize,
+    max_per_line: usize,
+}
+
+impl ScanCounts {
+    fn add_line(&mut self, matches: usize) {
+        self.matches += matches;
+        self.lines += usize::from(matches > 0);
+        self.max_per_line = self.max_per_line.max(matches);
+    }
+
+    fn merge(&mut self, other: ScanCounts) {
+        self.matches += other.matches;
+        self.lines += other.lines;
+        self.max_per_line = self.max_per_line.max(other.max_per_line);
     }
 }
 
-/// Render aggregated netblocks into one output string per line.
-/// In range mode, adjacent CIDRs are merged into contiguous ranges.
-fn format_blocks<T: Aggregateable>(blocks: &[T], fmt: OutputFormat) -> Vec<String> {
-    if fmt != OutputFormat::Range {
-        return blocks.iter().map(|nb| format_block(nb, fmt)).collect();
+impl FormCounts {
+    fn add(&mut self, form: InputForm) {
+        match form {
+            InputForm::Cidr => self.cidr += 1,
+            InputForm::Address => self.address += 1,
+            InputForm::Mask => self.mask += 1,
+            InputForm::Range => self.range += 1,
+            InputForm::Pattern => self.pattern += 1,
+            InputForm::Integer => self.integer += 1,
+        }
     }
 
-    let mut out = Vec::new();
-    if blocks.is_empty() {
-        return out;
+    fn merge(&mut self, other: FormCounts) {
+        self.cidr += other.cidr;
+        self.address += other.address;
+        self.mask += other.mask;
+        self.range += other.range;
+        self.pattern += other.pattern;
+        self.integer += other.integer;
     }
-    let mut range_start = blocks[0].display_start();
-    let mut range_end = blocks[0].display_end();
-    for pair in blocks.windows(2) {
-        if pair[0].is_contiguous_with(&pair[1]) {
-            // Extend the current range
-            range_end = pair[1].display_end();
-        } else {
-            // Emit the completed range and start a new one
-            out.push(format!("{}-{}", range_start, range_end));
-            range_start = pair[1].display_start();
-            range_end = pair[1].display_end();
+}
+
+/// Destination for rejected input lines (--rejects FILE, or stderr for "-").
+/// Each line is written as "SOURCE:LINE: REASON: ORIGINAL".
+#[derive(Default)]
+struct RejectSink {
+    out: Option<Box<dyn Write>>,
+    /// The diagnostics go to stderr (--rejects -)
+    stderr: bool,
+}
+
+impl RejectSink {
+    fn create(path: &str) -> io::Result<Self> {
+        if path == REJECTS_STDERR {
+            return Ok(Self { out: Some(Box::new(io::stderr())), stderr: true });
+        }
+        let file = std::fs::File::create(path)?;
+        Ok(Self { out: Some(Box::new(io::BufWriter::new(file))), stderr: false })
+    }
+
+    /// Write one rejected-line diagnostic; a no-op unless --rejects was given.
+    fn record(&mut self, diagnostic: &str) -> io::Result<()> {
+        match self.out {
+            Some(ref mut w) => writeln!(w, "{}", diagnostic),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.out {
+            Some(ref mut w) => w.flush(),
+            None => Ok(()),
         }
     }
-    // Emit the final range
-    out.push(format!("{}-{}", range_start, range_end));
-    out
 }
 
-/// Write aggregated netblocks to `out` in the selected format.
-fn write_netblocks<T: Aggregateable>(blocks: &[T], fmt: OutputFormat, out: &mut impl Write) {
-    for line in format_blocks(blocks, fmt) {
-        let _ = writeln!(out, "{}", line);
+/// --rejects argument that sends the diagnostics to stderr.
+const REJECTS_STDERR: &str = "-";
+
+/// Collected parse results from reading an input source.
+#[derive(Default)]
+struct ParseResult {
+    v4: Vec<NetblockV4>,
+    v6: Vec<NetblockV6>,
+    total_lines: usize,
+    invalid_lines: usize,
+    /// Lines skipped because they contained invalid UTF-8
+    /// (only counted when --delimiter/--fields are active)
+    utf8_invalid_lines: usize,
+    /// Every rejected line, including invalid UTF-8 in the original mode,
+    /// which the counters above do not include, but not the lines of a
+    /// family left out with -4/-6
+    rejected_lines: usize,
+    /// Diagnostics of the first `MAX_REPORTED_REJECTS` rejected lines
+    first_rejects: Vec<String>,
+    /// Accepted items per input form
+    forms: FormCounts,
+    /// Matches found by --scan
+    scan: ScanCounts,
+    /// Netblocks with the line they were read from (--keep-comments and
+    /// --explain)
+    origins_v4: Vec<(NetblockV4, Origin)>,
+    origins_v6: Vec<(NetblockV6, Origin)>,
+    /// Group key of each netblock in `v4`/`v6` (--group-by-field)
+    groups_v4: Vec<Rc<str>>,
+    groups_v6: Vec<Rc<str>>,
+}
+
+impl ParseResult {
+    /// Append the netblocks and counters of `other` to `self`.
+    fn merge(&mut self, other: ParseResult) {
+        self.v4.extend(other.v4);
+        self.v6.extend(other.v6);
+        self.total_lines += other.total_lines;
+        self.invalid_lines += other.invalid_lines;
+        self.utf8_invalid_lines += other.utf8_invalid_lines;
+        self.rejected_lines += other.rejected_lines;
+        self.forms.merge(other.forms);
+        self.scan.merge(other.scan);
+        self.origins_v4.extend(other.origins_v4);
+        self.origins_v6.extend(other.origins_v6);
+        self.groups_v4.extend(other.groups_v4);
+        self.groups_v6.extend(other.groups_v6);
+        let room = MAX_REPORTED_REJECTS.saturating_sub(self.first_rejects.len());
+        self.first_rejects.extend(other.first_rejects.into_iter().take(room));
+    }
+
+    /// Count a rejected line, keeping its diagnostic for --strict errors and
+    /// passing it on to the --rejects sink.
+    fn reject(
+        &mut self,
+        rejects: &mut RejectSink,
+        source: &str,
+        line_no: u64,
+        reason: &dyn fmt::Display,
+        text: &str,
+    ) -> io::Result<()> {
+        let diagnostic = format!("{}:{}: {}: {}", source, line_no, reason, text);
+        self.rejected_lines += 1;
+        if self.first_rejects.len() < MAX_REPORTED_REJECTS {
+            self.first_rejects.push(diagnostic.clone());
+        }
+        rejects.record(&diagnostic)
+    }
+
+    /// Count a line the parsers rejected with `e`.  Lines of a family left
+    /// out with -4/-6 still go to the --rejects sink, but are only filtered:
+    /// they do not count against --strict/--max-invalid.
+    fn reject_parse(
+        &mut self,
+        rejects: &mut RejectSink,
+        source: &str,
+        line_no: u64,
+        e: &NetblockParseError,
+        text: &str,
+    ) -> io::Result<()> {
+        if *e == NetblockParseError::WrongFamily {
+            return rejects.record(&format!("{}:{}: {}: {}", source, line_no, e, text));
+        }
+        self.reject(rejects, source, line_no, e, text)
+    }
+
+    /// Record `origin` for the netblocks added since `v4_start`/`v6_start`.
+    fn record_origin(&mut self, v4_start: usize, v6_start: usize, origin: &Origin) {
+        let v4 = self.v4[v4_start..].iter().map(|nb| (*nb, origin.clone()));
+        self.origins_v4.extend(v4);
+        let v6 = self.v6[v6_start..].iter().map(|nb| (*nb, origin.clone()));
+        self.origins_v6.extend(v6);
+    }
+
+    /// Record group `key` for the netblocks added since `v4_start`/`v6_start`.
+    fn record_group(&mut self, v4_start: usize, v6_start: usize, key: &Rc<str>) {
+        let v4 = self.v4.len() - v4_start;
+        self.groups_v4.extend(std::iter::repeat_n(Rc::clone(key), v4));
+        let v6 = self.v6.len() - v6_start;
+        self.groups_v6.extend(std::iter::repeat_n(Rc::clone(key), v6));
+    }
+
+    /// Number of lines examined: accepted lines plus every rejected one, so
+    /// not the lines filtered by family.  Every invalid line is counted in
+    /// `total_lines` as well.
+    fn examined_lines(&self) -> usize {
+        self.total_lines - self.invalid_lines + self.rejected_lines
     }
 }
 
-/// Sum address counts across all netblocks, returning an exact decimal string.
-/// Counts are accumulated in u128; the only total that does not fit is the
-/// whole IPv6 space (2^128 = u128::MAX + 1), which is reported as such.
-fn total_addresses_string<T: Aggregateable>(blocks: &[T]) -> String {
-    // 2^128 in decimal (u128::MAX + 1); the single value that overflows u128.
-    const FULL_IPV6_SPACE: &str = "340282366920938463463374607431768211456";
+/// How many rejected lines are quoted when a --strict/--max-invalid limit fails.
+const MAX_REPORTED_REJECTS: usize = 5;
 
-    let mut sum: u128 = 0;
-    for b in blocks {
-        match b.address_count() {
-            // None marks the IPv6 /0 block, whose count is exactly 2^128.
-            None => return FULL_IPV6_SPACE.to_string(),
-            Some(c) => match sum.checked_add(c) {
-                Some(s) => sum = s,
-                // Non-overlapping blocks total at most 2^128; overflowing u128
-                // therefore means the sum is exactly 2^128.
-                None => return FULL_IPV6_SPACE.to_string(),
-            },
+/// Exit status when an input exceeds the --strict/--max-invalid limits.
+const EXIT_INVALID_INPUT: i32 = 3;
+
+/// Limits on rejected lines per input (--strict, --max-invalid,
+/// --max-invalid-percent); unset limits are not checked.
+struct InvalidLimits {
+    max_count: Option<usize>,
+    max_percent: Option<f64>,
+}
+
+impl InvalidLimits {
+    /// Exit with `EXIT_INVALID_INPUT` and a diagnostic quoting the first
+    /// offending lines if `parsed` (the input described by `what`) exceeds a limit.
+    fn enforce(&self, what: &str, parsed: &ParseResult, rejects: &mut RejectSink) {
+        if self.max_count.is_none() && self.max_percent.is_none() {
+            return;
+        }
+        let rejected = parsed.rejected_lines;
+        let examined = parsed.examined_lines();
+        let percent = if examined == 0 { 0.0 } else { rejected as f64 * 100.0 / examined as f64 };
+
+        let exceeded = if let Some(max) = self.max_count
+            && rejected > max
+        {
+            format!("more than {}", max)
+        } else if let Some(max) = self.max_percent
+            && percent > max
+        {
+            format!("more than {}%", max)
+        } else {
+            return;
+        };
+
+        // process::exit skips destructors, so flush the --rejects file first
+        let _ = rejects.flush();
+        let mut stderr = io::stderr().lock();
+        let _ = writeln!(
+            stderr,
+            "error: {}: {} of {} lines rejected ({:.2}%), {} allowed",
+            what, rejected, examined, percent, exceeded
+        );
+        // With --rejects - every diagnostic is on stderr already
+        if !rejects.stderr {
+            for diagnostic in &parsed.first_rejects {
+                let _ = writeln!(stderr, "  {}", diagnostic);
+            }
+            if rejected > parsed.first_rejects.len() {
+                let _ =
+                    writeln!(stderr, "  ... and {} more", rejected - parsed.first_rejects.len());
+            }
         }
+        std::process::exit(EXIT_INVALID_INPUT);
     }
-    sum.to_string()
 }
 
-// ---------------------------------------------------------------------------
-// JSON output (dependency-free; results are arrays of plain strings)
-// ---------------------------------------------------------------------------
+/// Line counters of a single input source, kept for per-file --stats.
+struct SourceStats {
+    name: String,
+    total_lines: usize,
+    invalid_lines: usize,
+    utf8_invalid_lines: usize,
+}
 
-/// Escape a string for safe inclusion inside a JSON string literal.
-fn json_escape(s: &str) -> String {
-    let mut out = String::with_capacity(s.len() + 2);
-    for c in s.chars() {
-        match c {
-            '"' => out.push_str("\\\""),
-            '\\' => out.push_str("\\\\"),
-            '\n' => out.push_str("\\n"),
-            '\r' => out.push_str("\\r"),
-            '\t' => out.push_str("\\t"),
-            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
-            c => out.push(c),
+impl SourceStats {
+    fn new(name: &str, parsed: &ParseResult) -> Self {
+        Self {
+            name: name.to_string(),
+            total_lines: parsed.total_lines,
+            invalid_lines: parsed.invalid_lines,
+            utf8_invalid_lines: parsed.utf8_invalid_lines,
         }
     }
-    out
 }
 
-/// `"key": [ ... ]` member with each element on its own line, indented.
-fn json_array_member(key: &str, items: &[String], indent: usize) -> String {
-    let pad = "  ".repeat(indent);
-    if items.is_empty() {
-        return format!("{}\"{}\": []", pad, json_escape(key));
+/// Path argument that stands for standard input.
+const STDIN_PATH: &str = "-";
+
+/// Open an input source for reading; "-" means stdin.  Input compressed
+/// with gzip, xz, zstd or bzip2 is recognised by its magic bytes and
+/// decompressed on the fly.
+fn open_input(path: &str) -> io::Result<Box<dyn io::Read>> {
+    let input: Box<dyn io::Read> = if path == STDIN_PATH {
+        Box::new(io::stdin())
+    } else {
+        Box::new(std::fs::File::open(path)?)
+    };
+    decompress(input)
+}
+
+/// Magic bytes of the supported compression formats.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+
+/// Wrap `input` in a decoder if it starts with a known magic number.
+/// Concatenated streams (as produced by appending to a .gz file) are
+/// decoded in full.
+fn decompress(mut input: Box<dyn io::Read>) -> io::Result<Box<dyn io::Read>> {
+    // Read up to the longest magic; a pipe may deliver it in pieces
+    let mut head = [0u8; XZ_MAGIC.len()];
+    let mut len = 0;
+    while len < head.len() {
+        match input.read(&mut head[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
     }
-    let ipad = "  ".repeat(indent + 1);
-    let body = items
-        .iter()
-        .map(|it| format!("{}\"{}\"", ipad, json_escape(it)))
-        .collect::<Vec<_>>()
-        .join(",\n");
-    format!("{}\"{}\": [\n{}\n{}]", pad, json_escape(key), body, pad)
+    let head = &head[..len];
+    let stream = io::Cursor::new(head.to_vec()).chain(input);
+    Ok(if head.starts_with(GZIP_MAGIC) {
+        Box::new(flate2::read::MultiGzDecoder::new(stream))
+    } else if head.starts_with(XZ_MAGIC) {
+        Box::new(liblzma::read::XzDecoder::new_multi_decoder(stream))
+    } else if head.starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::stream::read::Decoder::new(stream)?)
+    } else if head.starts_with(BZIP2_MAGIC) {
+        Box::new(bzip2::read::MultiBzDecoder::new(stream))
+    } else {
+        Box::new(stream)
+    })
 }
 
-/// `"key": <number>` member.
-fn json_num_member(key: &str, val: u64, indent: usize) -> String {
-    format!("{}\"{}\": {}", "  ".repeat(indent), json_escape(key), val)
+/// Append every regular file below `dir` to `out`, walking subdirectories
+/// recursively.  Entries are visited in name order so the per-file stats are
+/// stable; symlinked directories are not followed to avoid cycles.
+fn collect_dir_files(dir: &Path, out: &mut Vec<String>) -> io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_dir_files(&path, out)?;
+        } else if path.is_file() {
+            out.push(path.to_string_lossy().into_owned());
+        }
+    }
+    Ok(())
 }
 
-/// `"key": "<string>"` member.
-fn json_str_member(key: &str, val: &str, indent: usize) -> String {
-    format!("{}\"{}\": \"{}\"", "  ".repeat(indent), json_escape(key), json_escape(val))
+/// Expand positional FILE arguments into the list of sources to read.
+/// No arguments means stdin.  Existing paths are used as-is (directories are
+/// walked recursively); arguments that do not exist but contain glob
+/// metacharacters are expanded, and a pattern matching nothing is an error.
+fn expand_inputs(args: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+    if args.is_empty() {
+        return Ok(vec![STDIN_PATH.to_string()]);
+    }
+
+    let mut sources = Vec::new();
+    for arg in args {
+        let path = Path::new(arg);
+        if arg == STDIN_PATH || path.exists() || !arg.contains(['*', '?', '[']) {
+            if path.is_dir() {
+                collect_dir_files(path, &mut sources)?;
+            } else {
+                // Missing plain files are reported when they are opened.
+                sources.push(arg.clone());
+            }
+            continue;
+        }
+
+        let mut matched = false;
+        for entry in glob::glob(arg).map_err(|e| format!("invalid pattern {:?}: {}", arg, e))? {
+            let m = entry?;
+            matched = true;
+            if m.is_dir() {
+                collect_dir_files(&m, &mut sources)?;
+            } else {
+                sources.push(m.to_string_lossy().into_owned());
+            }
+        }
+        if !matched {
+            return Err(format!("no files match pattern {:?}", arg).into());
+        }
+    }
+    Ok(sources)
 }
 
-/// `"key": { ... }` member from already-rendered inner members.
-fn json_object_member(key: &str, inner: &[String], indent: usize) -> String {
-    let pad = "  ".repeat(indent);
-    if inner.is_empty() {
-        return format!("{}\"{}\": {{}}", pad, json_escape(key));
+/// Apply --max-length clamping to a vector of IPv4 netblocks.
+fn apply_max_length_v4(blocks: &mut [NetblockV4], max_len: u8) {
+    let max = max_len.min(32);
+    for nb in blocks.iter_mut() {
+        if nb.prefix_len() > max {
+            *nb = NetblockV4::new(nb.network(), max);
+        }
     }
-    format!("{}\"{}\": {{\n{}\n{}}}", pad, json_escape(key), inner.join(",\n"), pad)
 }
 
-/// Wrap top-level members into a complete JSON object document.
-fn json_document(members: &[String]) -> String {
-    if members.is_empty() {
-        return "{}\n".to_string();
+/// Apply --max-length clamping to a vector of IPv6 netblocks.
+fn apply_max_length_v6(blocks: &mut [NetblockV6], max_len: u8) {
+    let max = max_len.min(128);
+    for nb in blocks.iter_mut() {
+        if nb.prefix_len() > max {
+            *nb = NetblockV6::new(nb.network(), max);
+        }
     }
-    format!("{{\n{}\n}}\n", members.join(",\n"))
 }
 
-/// Build the per-family stats sub-object (input/aggregated/addresses).
-/// Address counts are emitted as strings because IPv6 totals exceed the range
-/// of a JSON-safe integer.
-fn json_family_stats(key: &str, input: usize, aggregated: usize, addresses: &str) -> String {
-    let inner = vec![
-        json_num_member("input", input as u64, 3),
-        json_num_member("aggregated", aggregated as u64, 3),
-        json_str_member("addresses", addresses, 3),
-    ];
-    json_object_member(key, &inner, 2)
+/// Split `blocks` for --min-length, exiting with an error if the result
+/// would have more than `limit` prefixes.
+fn split_output<T: Aggregateable>(blocks: &[T], min_len: u8, limit: u64, family: &str) -> Vec<T> {
+    let count = split_count(blocks, min_len);
+    if count.is_none_or(|n| n > u128::from(limit)) {
+        let count = count.map_or("2^128".to_string(), |n| n.to_string());
+        eprintln!(
+            "error: --min-length {} would output {} {} prefixes, more than --split-limit {}",
+            min_len, count, family, limit
+        );
+        std::process::exit(1);
+    }
+    split_to_length(blocks, min_len)
 }
 
-// ---------------------------------------------------------------------------
-// Delimiter/field extraction (cut-like UTF-8 aware processing)
-// ---------------------------------------------------------------------------
+/// Form of a line accepted by `parse_prefix_line`.
+fn prefix_form(line: &str) -> InputForm {
+    match line.split_once('/') {
+        _ if line.contains(char::is_whitespace) => InputForm::Mask,
+        Some((_, mask)) if mask.contains(['.', ':']) => InputForm::Mask,
+        Some(_) => InputForm::Cidr,
+        None => InputForm::Address,
+    }
+}
 
-/// Options for delimiter-based field extraction (cut-like mode).
-struct FieldOptions {
-    delimiter: char,
-    fields: Vec<i32>,
+/// Expand an IPv4 address with a non-contiguous netmask or wildcard mask
+/// (--expand-wildcards), up to --expand-limit prefixes.
+fn expand_masked_v4(
+    line: &str,
+    opts: &ReadOptions,
+    out: &mut ParseResult,
+) -> Result<(), NetblockParseError> {
+    let (addr, wildcard) = parse_masked_v4(line)?;
+    if !opts.accept_v4 {
+        return Err(NetblockParseError::WrongFamily);
+    }
+    if opts.ignore_invalid && addr & wildcard != 0 {
+        return Err(NetblockParseError::HostBitsSet);
+    }
+    let count = wildcard_prefix_count(wildcard);
+    if count > opts.expand_limit {
+        return Err(NetblockParseError::TooManyPrefixes {
+            count: count.into(),
+            limit: opts.expand_limit.into(),
+        });
+    }
+    out.v4.extend(wildcard_to_prefixes_v4(addr, wildcard));
+    Ok(())
 }
 
-/// Parse a delimiter specification into a single Unicode scalar value.
-/// Accepts:
-///   - A single UTF-8 character (e.g. ":" or "→")
-///   - UXXXX or U+XXXX hex notation (e.g. "U003A" or "U+003A" for ':')
-///     Rejects CESU-8 surrogate code points (U+D800..U+DFFF).
-fn parse_delimiter(s: &str) -> Result<char, String> {
-    // Try U+XXXX or UXXXX format
-    let hex_str = if let Some(stripped) = s.strip_prefix("U+") {
-        Some(stripped)
+/// Parse `line` as a CIDR prefix, bare address or address with a netmask or
+/// wildcard mask, of either family.
+/// On failure, the error reported is the one from the family the text looks
+/// like (IPv6 if it contains ':'), which is `WrongFamily` when the text is
+/// valid but its family was excluded with -4/-6.
+fn parse_prefix_line(
+    line: &str,
+    opts: &ReadOptions,
+    out: &mut ParseResult,
+) -> Result<InputForm, NetblockParseError> {
+    let form = prefix_form(line);
+    // Try IPv4 CIDR / bare address first
+    let err4 = match line.parse::<NetblockV4>() {
+        Ok(_) if !opts.accept_v4 => NetblockParseError::WrongFamily,
+        Ok(nb) if opts.ignore_invalid && !nb.is_canonical() => NetblockParseError::HostBitsSet,
+        Ok(nb) => {
+            out.v4.push(nb.canonical());
+            return Ok(form);
+        }
+        Err(NetblockParseError::NonContiguousMask(_)) if opts.expand_wildcards => {
+            match expand_masked_v4(line, opts, out) {
+                Ok(()) => return Ok(form),
+                Err(e) => e,
+            }
+        }
+        Err(e) => e,
+    };
+    let err6 = match line.parse::<NetblockV6>() {
+        Ok(_) if !opts.accept_v6 => NetblockParseError::WrongFamily,
+        Ok(nb) if opts.ignore_invalid && !nb.is_canonical() => NetblockParseError::HostBitsSet,
+        Ok(nb) => {
+            out.v6.push(nb.canonical());
+            return Ok(form);
+        }
+        Err(e) => e,
+    };
+    Err(if line.contains(':') { err6 } else { err4 })
+}
+
+/// Parse `line` as a start-end range of either family; errors are chosen as
+/// in `parse_prefix_line`.
+fn parse_range_line(
+    line: &str,
+    opts: &ReadOptions,
+    out: &mut ParseResult,
+) -> Result<InputForm, NetblockParseError> {
+    // Try IPv4 range first (dotted-decimal never contains ':')
+    let err4 = match parse_range_v4(line) {
+        Ok((start, end)) if opts.accept_v4 => {
+            out.v4.extend(range_to_prefixes_v4(start, end));
+            return Ok(InputForm::Range);
+        }
+        Ok(_) => NetblockParseError::WrongFamily,
+        Err(e) => e,
+    };
+    let err6 = match parse_range_v6(line) {
+        Ok((start, end)) if opts.accept_v6 => {
+            out.v6.extend(range_to_prefixes_v6(start, end));
+            return Ok(InputForm::Range);
+        }
+        Ok(_) => NetblockParseError::WrongFamily,
+        Err(e) => e,
+    };
+    Err(if line.contains(':') { err6 } else { err4 })
+}
+
+/// Parse `line` as an nmap-style IPv4 pattern or short prefix (--input-nmap),
+/// up to --expand-limit prefixes.
+fn parse_nmap_line(
+    line: &str,
+    opts: &ReadOptions,
+    out: &mut ParseResult,
+) -> Result<InputForm, NetblockParseError> {
+    if line.contains(':') {
+        // IPv6 has no patterns; leave the error to the IPv6 parser
+        return Err(NetblockParseError::BadAddress(line.to_string()));
+    }
+    if line.contains('/') {
+        let nb = parse_short_prefix_v4(line)?;
+        if !opts.accept_v4 {
+            return Err(NetblockParseError::WrongFamily);
+        }
+        if opts.ignore_invalid && !nb.is_canonical() {
+            return Err(NetblockParseError::HostBitsSet);
+        }
+        out.v4.push(nb.canonical());
+        return Ok(InputForm::Pattern);
+    }
+    let pattern: OctetPattern = line.parse()?;
+    if !opts.accept_v4 {
+        return Err(NetblockParseError::WrongFamily);
+    }
+    let count = pattern.prefix_count();
+    if count > u128::from(opts.expand_limit) {
+        return Err(NetblockParseError::TooManyPrefixes { count, limit: opts.expand_limit.into() });
+    }
+    out.v4.extend(pattern.to_prefixes());
+    Ok(InputForm::Pattern)
+}
+
+/// Parse `line` as an integer address, "N/LEN" prefix or "N-M" range
+/// (--input-int).  IPv4 is chosen when every number fits in 32 bits (and
+/// the prefix length in /32) and IPv4 is accepted, IPv6 otherwise.
+fn parse_int_line(
+    line: &str,
+    opts: &ReadOptions,
+    out: &mut ParseResult,
+) -> Result<InputForm, NetblockParseError> {
+    let (start, end, prefix_len) = if let Some((start, end)) = line.split_once('-') {
+        let (start, end) = (parse_int_addr(start.trim())?, parse_int_addr(end.trim())?);
+        if start > end {
+            return Err(NetblockParseError::RangeOrder);
+        }
+        (start, end, None)
+    } else if let Some((addr, len)) = line.split_once('/') {
+        let addr = parse_int_addr(addr)?;
+        let len = len
+            .parse::<u8>()
+            .ok()
+            .filter(|&len| len <= 128)
+            .ok_or_else(|| NetblockParseError::BadPrefix(len.to_string()))?;
+        (addr, addr, Some(len))
     } else {
-        s.strip_prefix('U').filter(|&stripped| {
-            !stripped.is_empty() && stripped.chars().all(|c| c.is_ascii_hexdigit())
-        })
+        let addr = parse_int_addr(line)?;
+        (addr, addr, None)
     };
 
-    if let Some(hex) = hex_str {
-        if hex.is_empty() {
-            return Err("empty code point after 'U' prefix".to_string());
+    let fits_v4 = end <= u128::from(u32::MAX) && prefix_len.is_none_or(|len| len <= 32);
+    if fits_v4 && opts.accept_v4 {
+        let (start, end) = (start as u32, end as u32);
+        match prefix_len {
+            Some(len) => {
+                let nb = NetblockV4::new(Ipv4Addr::from(start), len);
+                if opts.ignore_invalid && u32::from(nb.network()) != start {
+                    return Err(NetblockParseError::HostBitsSet);
+                }
+                out.v4.push(nb);
+            }
+            None => out.v4.extend(range_to_prefixes_v4(start, end)),
         }
-        let code_point =
-            u32::from_str_radix(hex, 16).map_err(|_| format!("invalid Unicode escape: {}", s))?;
-        // Reject CESU-8 surrogates
-        if (0xD800..=0xDFFF).contains(&code_point) {
-            return Err(format!("CESU-8 surrogate code point U+{:04X} is not allowed", code_point));
+    } else if opts.accept_v6 {
+        match prefix_len {
+            Some(len) => {
+                let nb = NetblockV6::new(Ipv6Addr::from(start), len);
+                if opts.ignore_invalid && u128::from(nb.network()) != start {
+                    return Err(NetblockParseError::HostBitsSet);
+                }
+                out.v6.push(nb);
+            }
+            None => out.v6.extend(range_to_prefixes_v6(start, end)),
         }
-        char::from_u32(code_point)
-            .ok_or_else(|| format!("invalid Unicode code point: U+{:04X}", code_point))
     } else {
-        // Must be exactly one Unicode scalar value (one grapheme cluster of one code point)
-        let graphemes: Vec<&str> = s.graphemes(true).collect();
-        if graphemes.len() != 1 {
-            return Err(format!(
-                "delimiter must be a single character, got {} grapheme cluster(s): {:?}",
-                graphemes.len(),
-                s
-            ));
+        return Err(NetblockParseError::WrongFamily);
+    }
+    Ok(InputForm::Integer)
+}
+
+/// Signature shared by the per-syntax line parsers.
+type LineParser = fn(&str, &ReadOptions, &mut ParseResult) -> Result<InputForm, NetblockParseError>;
+
+/// Try to parse a single line in the configured input mode, adding the
+/// netblocks to `out` and counting the form that matched.
+fn process_line(
+    line: &str,
+    opts: &ReadOptions,
+    out: &mut ParseResult,
+) -> Result<(), NetblockParseError> {
+    if line.is_empty() {
+        return Err(NetblockParseError::Empty);
+    }
+    let parsed = match opts.input_mode {
+        InputMode::Cidr => parse_prefix_line(line, opts, out),
+        InputMode::Range => parse_range_line(line, opts, out),
+        InputMode::Auto => match parse_prefix_line(line, opts, out) {
+            Ok(form) => Ok(form),
+            // A '-' never appears in a prefix, so report the range error
+            // for text that looks like a range
+            Err(err) => match parse_range_line(line, opts, out) {
+                Ok(form) => Ok(form),
+                Err(range_err) if line.contains('-') => Err(range_err),
+                Err(_) => Err(err),
+            },
+        },
+    };
+    // Optional extra syntaxes; text an alternative does not recognise at
+    // all (BadAddress) keeps the error found so far
+    let alternatives: [(bool, LineParser); 2] =
+        [(opts.nmap, parse_nmap_line), (opts.int, parse_int_line)];
+    let mut parsed = parsed;
+    for (enabled, parse) in alternatives {
+        if let Err(ref err) = parsed
+            && enabled
+        {
+            parsed = match parse(line, opts, out) {
+                Err(NetblockParseError::BadAddress(_)) => Err(err.clone()),
+                alt => alt,
+            };
         }
-        let mut chars = graphemes[0].chars();
-        let ch = chars.next().ok_or_else(|| "empty delimiter".to_string())?;
-        if chars.next().is_some() {
-            return Err(format!(
-                "delimiter must be a single code point, \
-                 {:?} is a multi-code-point grapheme cluster",
-                graphemes[0]
-            ));
+    }
+    out.forms.add(parsed?);
+    Ok(())
+}
+
+/// Try every selected field of a delimited line; the line is accepted if at
+/// least one field parses.  Otherwise the first field's error is returned.
+fn process_fields(
+    line: &str,
+    fopts: &FieldOptions,
+    opts: &ReadOptions,
+    out: &mut ParseResult,
+) -> Result<(), NetblockParseError> {
+    let parts: Vec<&str> = line.split(fopts.delimiter).collect();
+    let values = fopts.fields.iter().map(|&field_num| extract_field(&parts, field_num));
+    process_values(values, opts, out)
+}
+
+/// Try every selected capture group of the first --regex match in a line,
+/// like `process_fields`; a group that did not match counts as empty.
+fn process_regex(
+    line: &str,
+    ropts: &RegexOptions,
+    opts: &ReadOptions,
+    out: &mut ParseResult,
+) -> Result<(), NetblockParseError> {
+    let caps = ropts.regex.captures(line);
+    let values = ropts
+        .groups
+        .iter()
+        .map(|&group| caps.as_ref().and_then(|c| c.get(group)).map(|m| m.as_str()));
+    process_values(values, opts, out)
+}
+
+/// Parse each extracted value (None for a missing one); succeeds if at
+/// least one parses, otherwise returns the first error.
+fn process_values<'a>(
+    values: impl Iterator<Item = Option<&'a str>>,
+    opts: &ReadOptions,
+    out: &mut ParseResult,
+) -> Result<(), NetblockParseError> {
+    let mut any_parsed = false;
+    let mut first_err = None;
+
+    for value in values {
+        match process_line(value.map_or("", str::trim), opts, out) {
+            Ok(()) => any_parsed = true,
+            Err(e) => {
+                first_err.get_or_insert(e);
+            }
         }
-        Ok(ch)
+    }
+
+    match first_err {
+        Some(e) if !any_parsed => Err(e),
+        _ => Ok(()),
     }
 }
 
-/// Parse a comma-separated list of 1-based field numbers.
-/// Positive numbers index from the start (1 = first).
-/// Negative numbers index from the end (-1 = last).
-/// Zero is not a valid field number.
-fn parse_field_spec(s: &str) -> Result<Vec<i32>, String> {
-    let mut fields = Vec::new();
-    for part in s.split(',') {
-        let part = part.trim();
-        if part.is_empty() {
+/// Whether `c` can be part of an address, prefix or range (--scan).
+fn is_scan_char(c: char) -> bool {
+    c.is_ascii_hexdigit() || matches!(c, '.' | ':' | '/' | '-')
+}
+
+/// Find every address, prefix and range in `line` (--scan), adding the
+/// netblocks to `out`.  Returns the number of matches.
+fn scan_line(line: &str, opts: &ReadOptions, out: &mut ParseResult) -> usize {
+    let mut matches = 0;
+    let mut start = 0;
+    for token in line.split(|c| !is_scan_char(c)) {
+        // Hex letters cut from a longer word ("Note::" gives "e::") are no address
+        let in_word = line[..start].ends_with(char::is_alphanumeric);
+        start += token.len() + line[start + token.len()..].chars().next().map_or(0, char::len_utf8);
+        let token = trim_scan_token(token);
+        if in_word && !token.contains(|c: char| c.is_ascii_digit()) {
             continue;
         }
-        let n: i32 = part.parse().map_err(|_| format!("invalid field number: {:?}", part))?;
-        if n == 0 {
-            return Err("field numbers are 1-based, 0 is not valid".to_string());
+        if scan_item(token, opts, out) {
+            matches += 1;
+        } else if token.contains('-') {
+            // "host-10.0.0.1" and the like: try the pieces around dashes
+            for part in token.split('-') {
+                matches += usize::from(scan_item(trim_scan_token(part), opts, out));
+            }
+        }
+    }
+    matches
+}
+
+/// Strip punctuation around a --scan token ("10.0.0.1." at the end of a
+/// sentence, "addr:" before a colon) without breaking "::1" or "fe80::".
+fn trim_scan_token(token: &str) -> &str {
+    let mut token = token.trim_matches(['.', '-', '/']);
+    if token.ends_with(':') && !token.ends_with("::") {
+        token = &token[..token.len() - 1];
+    }
+    if token.starts_with(':') && !token.starts_with("::") {
+        token = &token[1..];
+    }
+    token
+}
+
+/// Recognise one --scan token as a prefix, address or range, or as an
+/// IPv4 address with a ":PORT" suffix.
+fn scan_item(item: &str, opts: &ReadOptions, out: &mut ParseResult) -> bool {
+    // Text without digits ("::", words made of hex letters) only matches
+    // as an IPv6 address written in hex letters ("dead::beef")
+    if !item.contains(|c: char| c.is_ascii_digit()) {
+        let addr = item.split_once('/').map_or(item, |(addr, _)| addr);
+        if !addr.contains(|c: char| c.is_ascii_hexdigit()) || addr.parse::<Ipv6Addr>().is_err() {
+            return false;
+        }
+    }
+    let parsed = match parse_prefix_line(item, opts, out) {
+        Err(_) if item.contains('-') => parse_range_line(item, opts, out),
+        Err(_) => match item.rsplit_once(':') {
+            Some((addr, port))
+                if addr.contains('.')
+                    && !addr.contains(':')
+                    && !port.is_empty()
+                    && port.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                parse_prefix_line(addr, opts, out)
+            }
+            _ => return false,
+        },
+        parsed => parsed,
+    };
+    match parsed {
+        Ok(form) => {
+            out.forms.add(form);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Read netblocks from a `BufRead` source, tracking line counts.
+/// `source` names the input in --rejects output.
+fn read_netblocks(
+    reader: &mut dyn BufRead,
+    source: &str,
+    opts: &ReadOptions,
+    rejects: &mut RejectSink,
+) -> Result<ParseResult, Box<dyn Error>> {
+    let mut result = ParseResult::default();
+    let mut buf = Vec::new();
+    let mut line_no = 0;
+    let source_name: Rc<str> = Rc::from(source);
+
+    // Read lines as raw bytes to handle non-UTF8 content
+    loop {
+        buf.clear();
+        // Name the source: read errors include corrupt compressed data
+        let bytes_read =
+            reader.read_until(b'\n', &mut buf).map_err(|e| format!("{}: {}", source, e))?;
+        if bytes_read == 0 {
+            break; // End of input
+        }
+        line_no += 1;
+
+        let line_str = match std::str::from_utf8(&buf) {
+            Ok(line_str) => Cow::Borrowed(line_str),
+            // Free-form text is scanned whatever its encoding
+            Err(_) if opts.scan => String::from_utf8_lossy(&buf),
+            Err(_) => {
+                // Delimiter/fields and regex modes validate UTF-8 strictly
+                // and count the line; the original mode tolerates it and
+                // just skips the line.
+                if opts.fields.is_some() || opts.regex.is_some() {
+                    result.utf8_invalid_lines += 1;
+                }
+                let text = String::from_utf8_lossy(&buf);
+                result.reject(
+                    rejects,
+                    source,
+                    line_no,
+                    &NetblockParseError::BadUtf8,
+                    text.trim_end_matches(['\r', '\n']),
+                )?;
+                continue;
+            }
+        };
+        let line_str = line_str.as_ref();
+        let Some(line) = line_payload(line_str, opts) else {
+            continue;
+        };
+        result.total_lines += 1;
+
+        let group = match (&opts.group_by, &opts.fields) {
+            (Some(GroupField::Field(field)), Some(fopts)) => {
+                let parts: Vec<&str> = line.split(fopts.delimiter).collect();
+                Some(extract_field(&parts, *field).map_or("", str::trim))
+            }
+            _ => None,
+        };
+        if group == Some("") {
+            result.invalid_lines += 1;
+            let text = line_str.trim_end_matches(['\r', '\n']);
+            result.reject(rejects, source, line_no, &"missing group key", text)?;
+            continue;
+        }
+        let (v4_start, v6_start) = (result.v4.len(), result.v6.len());
+        let parsed = if let Some(ref fopts) = opts.fields {
+            process_fields(line, fopts, opts, &mut result)
+        } else if let Some(ref ropts) = opts.regex {
+            process_regex(line, ropts, opts, &mut result)
+        } else if opts.scan {
+            let matches = scan_line(line, opts, &mut result);
+            result.scan.add_line(matches);
+            Ok(())
+        } else {
+            process_line(line, opts, &mut result)
+        };
+        if let Some(key) = group {
+            result.record_group(v4_start, v6_start, &Rc::from(key));
+        }
+        if let Err(e) = parsed {
+            result.invalid_lines += 1;
+            let text = line_str.trim_end_matches(['\r', '\n']);
+            result.reject_parse(rejects, source, line_no, &e, text)?;
+        } else if opts.keep_comments || opts.explain {
+            let comment = split_comment(line_str, &opts.comment_markers)
+                .1
+                .map(str::trim)
+                .filter(|comment| opts.keep_comments && !comment.is_empty());
+            if opts.explain || comment.is_some() {
+                let origin = Origin {
+                    source: Rc::clone(&source_name),
+                    line: line_no,
+                    comment: comment.map(Rc::from),
+                };
+                result.record_origin(v4_start, v6_start, &origin);
+            }
         }
-        fields.push(n);
     }
-    if fields.is_empty() {
-        return Err("no fields specified".to_string());
+
+    // Apply max-length clamping if requested
+    if let Some(max_len) = opts.max_length {
+        apply_max_length_v4(&mut result.v4, max_len);
+        apply_max_length_v6(&mut result.v6, max_len);
     }
-    Ok(fields)
+
+    Ok(result)
 }
 
-/// Extract a single field from a line split by delimiter.
-/// Positive field numbers are 1-based from the start.
-/// Negative field numbers count from the end (-1 = last field).
-fn extract_field<'a>(parts: &[&'a str], field: i32) -> Option<&'a str> {
-    let index = if field > 0 {
-        (field - 1) as usize
-    } else {
-        // Negative: count from end (-1 = last element)
-        let abs_field = (-field) as usize;
-        if abs_field > parts.len() {
-            return None;
-        }
-        parts.len() - abs_field
-    };
-    parts.get(index).copied()
+/// Open `path` ("-" for stdin) and read netblocks from it, in CSV mode when
+/// a CSV column was selected and line mode otherwise.
+fn read_source(
+    path: &str,
+    opts: &ReadOptions,
+    rejects: &mut RejectSink,
+) -> Result<ParseResult, Box<dyn Error>> {
+    let input = open_input(path).map_err(|e| format!("{}: {}", path, e))?;
+    match opts.csv {
+        Some(ref copts) => read_netblocks_csv(input, path, copts, opts, rejects),
+        None => read_netblocks(&mut io::BufReader::new(input), path, opts, rejects),
+    }
 }
 
 // ---------------------------------------------------------------------------
-// CSV field extraction
+// Line filter (--grep)
 // ---------------------------------------------------------------------------
 
-/// Specifies which column to extract from CSV input.
-enum CsvOptions {
-    /// Column by 0-based index (user provides 1-based, converted before storing).
-    ByNumber(usize),
-    /// Column by header name (first row is the header).
-    ByName(String),
+/// The aggregated set that --grep tests addresses against.
+struct GrepSet {
+    v4: IpSet4,
+    v6: IpSet6,
 }
 
-/// Read netblocks from a CSV source using the `csv` crate.
-/// Extracts the specified column from each row and feeds it through `process_line`.
-fn read_netblocks_csv(
-    reader: Box<dyn io::Read>,
-    csv_opts: &CsvOptions,
-    input_range: bool,
-    ignore_invalid: bool,
-    accept_v4: bool,
-    accept_v6: bool,
-    max_length: Option<u8>,
-) -> Result<ParseResult, Box<dyn Error>> {
-    let mut result = ParseResult {
-        v4: Vec::new(),
-        v6: Vec::new(),
-        total_lines: 0,
-        invalid_lines: 0,
-        utf8_invalid_lines: 0,
+/// Line counts of a --grep run, for --stats.
+#[derive(Default)]
+struct GrepCounts {
+    lines: usize,
+    selected: usize,
+}
+
+/// The addresses taken from `line`: extracted with --fields, --regex or
+/// the CSV column (`csv_column`) when given, else every address in the line
+/// as with --scan.
+fn line_netblocks(line: &str, csv_column: Option<usize>, opts: &ReadOptions) -> ParseResult {
+    let mut found = ParseResult::default();
+    let _ = if let Some(ref fopts) = opts.fields {
+        process_fields(line, fopts, opts, &mut found)
+    } else if let Some(ref ropts) = opts.regex {
+        process_regex(line, ropts, opts, &mut found)
+    } else if let Some(column) = csv_column {
+        let record = csv_record(line);
+        let field = record.as_ref().and_then(|r| r.get(column)).map_or("", str::trim);
+        process_line(field, opts, &mut found)
+    } else {
+        scan_line(line, opts, &mut found);
+        Ok(())
     };
+    found
+}
 
-    let col_index: usize;
-
-    match csv_opts {
-        CsvOptions::ByNumber(idx) => {
-            col_index = *idx;
-            let mut rdr = csv::ReaderBuilder::new().has_headers(false).from_reader(reader);
-
-            for row_result in rdr.records() {
-                let record = match row_result {
-                    Ok(r) => r,
-                    Err(e) => {
-                        // csv::Error can be a UTF-8 error or a parse error
-                        if matches!(e.kind(), csv::ErrorKind::Utf8 { .. }) {
-                            result.utf8_invalid_lines += 1;
-                        } else {
-                            result.invalid_lines += 1;
-                        }
-                        continue;
-                    }
-                };
-                result.total_lines += 1;
+/// Whether an address taken from `line` (see `line_netblocks`) lies inside
+/// `set`.  A prefix or range counts when it lies inside entirely.
+fn grep_match(line: &str, csv_column: Option<usize>, opts: &ReadOptions, set: &GrepSet) -> bool {
+    let found = line_netblocks(line, csv_column, opts);
+    found.v4.iter().any(|nb| set.v4.contains(nb)) || found.v6.iter().any(|nb| set.v6.contains(nb))
+}
 
-                if let Some(field_val) = record.get(col_index) {
-                    let trimmed = field_val.trim();
-                    if !trimmed.is_empty() {
-                        let parsed = process_line(
-                            trimmed,
-                            input_range,
-                            ignore_invalid,
-                            accept_v4,
-                            accept_v6,
-                            &mut result.v4,
-                            &mut result.v6,
-                        );
-                        if !parsed {
-                            result.invalid_lines += 1;
-                        }
-                    } else {
-                        result.invalid_lines += 1;
+/// Stream every input to `out`, keeping the lines with an address inside
+/// `set` (outside it with `invert`).  With --csv-field-name the header line
+/// of each input names the column and is always kept.
+fn grep_inputs(
+    sources: &[String],
+    set: &GrepSet,
+    opts: &ReadOptions,
+    invert: bool,
+    out: &mut impl Write,
+) -> Result<GrepCounts, Box<dyn Error>> {
+    let mut counts = GrepCounts::default();
+    let mut buf = Vec::new();
+    let scan = opts.fields.is_none() && opts.regex.is_none() && opts.csv.is_none();
+    for path in sources {
+        let input = open_input(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut reader = io::BufReader::new(input);
+        let mut csv_column = match opts.csv {
+            Some(CsvOptions::ByNumber(index)) => Some(index),
+            _ => None,
+        };
+        let mut csv_header = match opts.csv {
+            Some(CsvOptions::ByName(ref name)) => Some(name),
+            _ => None,
+        };
+        loop {
+            buf.clear();
+            let bytes_read =
+                reader.read_until(b'\n', &mut buf).map_err(|e| format!("{}: {}", path, e))?;
+            if bytes_read == 0 {
+                break;
+            }
+            let selected = if let Some(name) = csv_header.take() {
+                let header = csv_record(&String::from_utf8_lossy(&buf)).unwrap_or_default();
+                csv_column =
+                    Some(csv_header_index(&header, name).map_err(|e| format!("{}: {}", path, e))?);
+                true
+            } else {
+                counts.lines += 1;
+                // Extraction needs UTF-8; scanning takes what it can find
+                let matched = match std::str::from_utf8(&buf) {
+                    Ok(line) => {
+                        grep_match(line.trim_end_matches(['\r', '\n']), csv_column, opts, set)
                     }
-                } else {
-                    result.invalid_lines += 1;
+                    Err(_) if scan => grep_match(&String::from_utf8_lossy(&buf), None, opts, set),
+                    Err(_) => false,
+                };
+                if matched != invert {
+                    counts.selected += 1;
+                }
+                matched != invert
+            };
+            if selected {
+                out.write_all(&buf)?;
+                if !buf.ends_with(b"\n") {
+                    out.write_all(b"\n")?;
                 }
             }
         }
-        CsvOptions::ByName(name) => {
-            let mut rdr = csv::ReaderBuilder::new().has_headers(true).from_reader(reader);
+    }
+    Ok(counts)
+}
 
-            // Find the column index from the header row
-            let headers = rdr.headers()?.clone();
-            col_index = headers.iter().position(|h| h.trim() == name.trim()).ok_or_else(|| {
-                format!(
-                    "CSV header {:?} not found (available: {})",
-                    name,
-                    headers.iter().map(|h| format!("{:?}", h)).collect::<Vec<_>>().join(", ")
-                )
-            })?;
-
-            for row_result in rdr.records() {
-                let record = match row_result {
-                    Ok(r) => r,
-                    Err(e) => {
-                        if matches!(e.kind(), csv::ErrorKind::Utf8 { .. }) {
-                            result.utf8_invalid_lines += 1;
-                        } else {
-                            result.invalid_lines += 1;
-                        }
-                        continue;
-                    }
-                };
-                result.total_lines += 1;
+// ---------------------------------------------------------------------------
+// Lookup queries (--lookup)
+// ---------------------------------------------------------------------------
+
+/// Entries of a --lookup set, each with the comments listed for it.
+type LookupTrie<T> = PrefixTrie<T, Option<Rc<str>>>;
+
+/// An entry matching a --lookup query, formatted, with its distinct comments.
+struct LookupMatch {
+    prefix: String,
+    comments: Vec<Rc<str>>,
+}
+
+/// Outcome of one --lookup query: its matches, shortest prefix first, or
+/// the reason the query could not be parsed.
+struct LookupResult {
+    query: String,
+    matches: Result<Vec<LookupMatch>, NetblockParseError>,
+}
+
+/// The entries of `trie` containing `nb`: the longest one, or every one
+/// with `all`.
+fn lookup_in<T: Aggregateable>(
+    trie: &LookupTrie<T>,
+    nb: &T,
+    all: bool,
+    fmt: OutputFormat,
+) -> Vec<LookupMatch> {
+    let mut found = trie.covering(nb);
+    if !all {
+        found = found.split_off(found.len().saturating_sub(1));
+    }
+    found
+        .into_iter()
+        .map(|(prefix, values)| {
+            let mut comments: Vec<Rc<str>> = Vec::new();
+            for comment in values.iter().flatten() {
+                if !comments.contains(comment) {
+                    comments.push(Rc::clone(comment));
+                }
+            }
+            LookupMatch { prefix: format_block(prefix, fmt), comments }
+        })
+        .collect()
+}
+
+/// Answer one --lookup query, an address or prefix of either family.
+fn lookup_query(
+    query: &str,
+    tries: (&LookupTrie<NetblockV4>, &LookupTrie<NetblockV6>),
+    opts: &ReadOptions,
+    all: bool,
+    fmt: OutputFormat,
+) -> LookupResult {
+    let matches = match query.parse::<NetblockV4>() {
+        Ok(nb) if opts.accept_v4 => Ok(lookup_in(tries.0, &nb, all, fmt)),
+        Ok(_) => Err(NetblockParseError::WrongFamily),
+        Err(err4) => match query.parse::<NetblockV6>() {
+            Ok(nb) if opts.accept_v6 => Ok(lookup_in(tries.1, &nb, all, fmt)),
+            Ok(_) => Err(NetblockParseError::WrongFamily),
+            Err(err6) => Err(if query.contains(':') { err6 } else { err4 }),
+        },
+    };
+    LookupResult { query: query.to_string(), matches }
+}
 
-                if let Some(field_val) = record.get(col_index) {
-                    let trimmed = field_val.trim();
-                    if !trimmed.is_empty() {
-                        let parsed = process_line(
-                            trimmed,
-                            input_range,
-                            ignore_invalid,
-                            accept_v4,
-                            accept_v6,
-                            &mut result.v4,
-                            &mut result.v6,
-                        );
-                        if !parsed {
-                            result.invalid_lines += 1;
-                        }
+/// Write --lookup results as text: one "QUERY: ENTRY" line per match, with
+/// the entry's comments after `marker`.  Invalid queries go to stderr.
+fn write_lookups(results: &[LookupResult], marker: &str, out: &mut impl Write) {
+    for result in results {
+        match result.matches {
+            Ok(ref matches) if matches.is_empty() => {
+                let _ = writeln!(out, "{}: no match", result.query);
+            }
+            Ok(ref matches) => {
+                for m in matches {
+                    if m.comments.is_empty() {
+                        let _ = writeln!(out, "{}: {}", result.query, m.prefix);
                     } else {
-                        result.invalid_lines += 1;
+                        let comments = m.comments.join(", ");
+                        let _ =
+                            writeln!(out, "{}: {} {} {}", result.query, m.prefix, marker, comments);
                     }
-                } else {
-                    result.invalid_lines += 1;
                 }
             }
+            Err(ref e) => eprintln!("error: invalid query {:?}: {}", result.query, e),
         }
     }
+}
 
-    // Apply max-length clamping if requested
-    if let Some(max_len) = max_length {
-        apply_max_length_v4(&mut result.v4, max_len);
-        apply_max_length_v6(&mut result.v6, max_len);
-    }
+/// Exit status of --lookup when no query matched.
+const EXIT_LOOKUP_MISS: i32 = 1;
+
+/// Exit status of --lookup when the set or a query could not be read or
+/// parsed, as grep uses 2 for trouble.
+const EXIT_LOOKUP_ERROR: i32 = 2;
+
+/// Run every --lookup query against the set read from `set_path`, printing
+/// the results.  Returns the exit status: `EXIT_LOOKUP_ERROR` for an invalid
+/// query, else 0 if any query matched and `EXIT_LOOKUP_MISS` if none did.
+fn run_lookups(
+    set_path: &str,
+    cli: &Cli,
+    read_opts: &ReadOptions,
+    limits: &InvalidLimits,
+    rejects: &mut RejectSink,
+    fmt: OutputFormat,
+) -> Result<i32, Box<dyn Error>> {
+    // Record every entry with its comment, as --keep-comments/--explain do
+    let set_opts = ReadOptions { keep_comments: true, explain: true, ..read_opts.clone() };
+    let set = read_source(set_path, &set_opts, rejects)?;
+    limits.enforce(&format!("--lookup {}", set_path), &set, rejects);
+    rejects.flush()?;
+    let mut trie_v4 = LookupTrie::new();
+    for (nb, origin) in set.origins_v4 {
+        trie_v4.insert(nb, origin.comment);
+    }
+    let mut trie_v6 = LookupTrie::new();
+    for (nb, origin) in set.origins_v6 {
+        trie_v6.insert(nb, origin.comment);
+    }
+
+    let queries: Vec<String> = if cli.input.is_empty() {
+        let lines = io::stdin().lock().lines().collect::<Result<Vec<_>, _>>()?;
+        lines.iter().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect()
+    } else {
+        cli.input.clone()
+    };
+    let results: Vec<LookupResult> = queries
+        .iter()
+        .map(|q| lookup_query(q, (&trie_v4, &trie_v6), read_opts, cli.lookup_all, fmt))
+        .collect();
 
-    Ok(result)
+    let mut stdout = io::stdout().lock();
+    if cli.json {
+        let _ = write!(stdout, "{}", json_lookups(&results));
+    } else {
+        let marker = read_opts.comment_markers.first().map_or("#", String::as_str);
+        write_lookups(&results, marker, &mut stdout);
+    }
+    let _ = stdout.flush();
+    Ok(if results.iter().any(|r| r.matches.is_err()) {
+        EXIT_LOOKUP_ERROR
+    } else if results.iter().any(|r| r.matches.as_ref().is_ok_and(|m| !m.is_empty())) {
+        0
+    } else {
+        EXIT_LOOKUP_MISS
+    })
 }
 
-/// Open a file and read netblocks from it in CSV mode.
-fn read_netblocks_csv_from_file(
-    path: &str,
-    csv_opts: &CsvOptions,
-    input_range: bool,
-    ignore_invalid: bool,
-    accept_v4: bool,
-    accept_v6: bool,
-    max_length: Option<u8>,
-) -> Result<ParseResult, Box<dyn Error>> {
-    let file = std::fs::File::open(path)?;
-    read_netblocks_csv(
-        Box::new(file),
-        csv_opts,
-        input_range,
-        ignore_invalid,
-        accept_v4,
-        accept_v6,
-        max_length,
-    )
+/// JSON document of --lookup results.
+fn json_lookups(results: &[LookupResult]) -> String {
+    let objects: Vec<Vec<String>> = results
+        .iter()
+        .map(|result| {
+            let mut inner = vec![json_str_member("query", &result.query, 3)];
+            match result.matches {
+                Ok(ref matches) => {
+                    let matches: Vec<Vec<String>> = matches
+                        .iter()
+                        .map(|m| {
+                            let comments: Vec<String> =
+                                m.comments.iter().map(|c| c.to_string()).collect();
+                            vec![
+                                json_str_member("prefix", &m.prefix, 5),
+                                json_array_member("comments", &comments, 5),
+                            ]
+                        })
+                        .collect();
+                    inner.push(json_object_array_member("matches", &matches, 3));
+                }
+                Err(ref e) => inner.push(json_str_member("error", &e.to_string(), 3)),
+            }
+            inner
+        })
+        .collect();
+    json_document(&[json_object_array_member("lookups", &objects, 1)])
 }
 
 // ---------------------------------------------------------------------------
-// Input processing
+// Annotation (--annotate)
 // ---------------------------------------------------------------------------
 
-/// Collected parse results from reading an input source.
-struct ParseResult {
-    v4: Vec<NetblockV4>,
-    v6: Vec<NetblockV6>,
-    total_lines: usize,
-    invalid_lines: usize,
-    /// Lines skipped because they contained invalid UTF-8
-    /// (only counted when --delimiter/--fields are active)
-    utf8_invalid_lines: usize,
+/// Columns of the --annotate table.
+struct TableColumns {
+    prefix: CsvOptions,
+    /// Label columns; empty for every column but the prefix one
+    labels: Vec<CsvOptions>,
 }
 
-/// Apply --max-length clamping to a vector of IPv4 netblocks.
-fn apply_max_length_v4(blocks: &mut [NetblockV4], max_len: u8) {
-    let max = max_len.min(32);
-    for nb in blocks.iter_mut() {
-        if nb.prefix_len > max {
-            *nb = NetblockV4::new(nb.network, max);
-        }
+/// Prefixes of the --annotate table with the labels of their rows.
+struct AnnotateTable {
+    v4: PrefixTrie<NetblockV4, Rc<[String]>>,
+    v6: PrefixTrie<NetblockV6, Rc<[String]>>,
+    /// Label column names, for the header line of CSV input
+    names: Vec<String>,
+}
+
+/// Line counts of an --annotate run, for --stats.
+#[derive(Default)]
+struct AnnotateCounts {
+    lines: usize,
+    annotated: usize,
+}
+
+/// Index of `column` in a table row; names need the `header` row.
+fn table_column_index(
+    column: &CsvOptions,
+    header: Option<&csv::StringRecord>,
+) -> Result<usize, String> {
+    match (column, header) {
+        (CsvOptions::ByNumber(index), _) => Ok(*index),
+        (CsvOptions::ByName(name), Some(header)) => csv_header_index(header, name),
+        (CsvOptions::ByName(name), None) => Err(format!("CSV header {:?} without header", name)),
     }
 }
 
-/// Apply --max-length clamping to a vector of IPv6 netblocks.
-fn apply_max_length_v6(blocks: &mut [NetblockV6], max_len: u8) {
-    let max = max_len.min(128);
-    for nb in blocks.iter_mut() {
-        if nb.prefix_len > max {
-            *nb = NetblockV6::new(nb.network, max);
+/// Read the --annotate table: the prefix column of each row is parsed like
+/// an input line, and every netblock it yields is labelled with the row's
+/// label columns.  The returned `ParseResult` counts rows for the
+/// --strict/--max-invalid limits.
+fn read_annotate_table(
+    path: &str,
+    columns: &TableColumns,
+    opts: &ReadOptions,
+    rejects: &mut RejectSink,
+) -> Result<(AnnotateTable, ParseResult), Box<dyn Error>> {
+    let input = open_input(path).map_err(|e| format!("{}: {}", path, e))?;
+    let has_headers = matches!(columns.prefix, CsvOptions::ByName(_))
+        || columns.labels.iter().any(|c| matches!(c, CsvOptions::ByName(_)));
+    let raw = Rc::new(RefCell::new(RawBytes::default()));
+    let input = RawTail { inner: input, raw: Rc::clone(&raw) };
+    let mut rdr =
+        csv::ReaderBuilder::new().has_headers(has_headers).flexible(true).from_reader(input);
+    let header = if has_headers { Some(rdr.headers()?.clone()) } else { None };
+    let resolve = |c: &CsvOptions| {
+        table_column_index(c, header.as_ref()).map_err(|e| format!("{}: {}", path, e))
+    };
+    let prefix_index = resolve(&columns.prefix)?;
+    let mut label_indexes = columns.labels.iter().map(resolve).collect::<Result<Vec<_>, _>>()?;
+
+    let mut table =
+        AnnotateTable { v4: PrefixTrie::new(), v6: PrefixTrie::new(), names: Vec::new() };
+    let mut result = ParseResult::default();
+    let mut first = true;
+    let mut record = csv::StringRecord::new();
+    loop {
+        let (row_result, text) = read_raw_record(&mut rdr, &raw, &mut record);
+        match row_result {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                if matches!(e.kind(), csv::ErrorKind::Io(_)) {
+                    return Err(format!("{}: {}", path, e).into());
+                }
+                let line_no = e.position().map_or(0, |p| p.line());
+                result.total_lines += 1;
+                result.invalid_lines += 1;
+                result.reject(rejects, path, line_no, &e, &text)?;
+                continue;
+            }
+        }
+        // Without label columns given, take every other column of the first row
+        if first && columns.labels.is_empty() {
+            let width = header.as_ref().unwrap_or(&record).len();
+            label_indexes = (0..width).filter(|&i| i != prefix_index).collect();
+        }
+        first = false;
+        result.total_lines += 1;
+
+        let field_val = record.get(prefix_index).map_or("", str::trim);
+        let (v4_start, v6_start) = (result.v4.len(), result.v6.len());
+        if let Err(e) = process_line(field_val, opts, &mut result) {
+            let line_no = record.position().map_or(0, |p| p.line());
+            result.invalid_lines += 1;
+            result.reject_parse(rejects, path, line_no, &e, &text)?;
+            continue;
+        }
+        let labels: Rc<[String]> =
+            label_indexes.iter().map(|&i| record.get(i).unwrap_or("").to_string()).collect();
+        for nb in &result.v4[v4_start..] {
+            table.v4.insert(*nb, Rc::clone(&labels));
+        }
+        for nb in &result.v6[v6_start..] {
+            table.v6.insert(*nb, Rc::clone(&labels));
         }
     }
+
+    table.names = label_indexes
+        .iter()
+        .map(|&i| match header.as_ref().and_then(|h| h.get(i)) {
+            Some(name) => name.to_string(),
+            None => format!("label{}", i + 1),
+        })
+        .collect();
+    Ok((table, result))
 }
 
-/// Try to parse a single line, dispatching to the appropriate address family.
-/// Returns true if the line was successfully parsed into at least one netblock.
-fn process_line(
+/// The labels of the longest table prefix containing an address taken from
+/// `line` (see `line_netblocks`); the first address with a match wins, and
+/// a prefix listed on several rows takes the labels of the first one.
+fn annotate_match<'a>(
     line: &str,
-    input_range: bool,
-    ignore_invalid: bool,
-    accept_v4: bool,
-    accept_v6: bool,
-    v4: &mut Vec<NetblockV4>,
-    v6: &mut Vec<NetblockV6>,
-) -> bool {
-    if input_range {
-        // Try IPv4 range first (dotted-decimal never contains ':')
-        if accept_v4 && let Some((start, end)) = parse_range_v4(line) {
-            v4.extend(range_to_prefixes_v4(start, end));
-            return true;
-        }
-        if accept_v6 && let Some((start, end)) = parse_range_v6(line) {
-            v6.extend(range_to_prefixes_v6(start, end));
-            return true;
-        }
-        false
+    csv_column: Option<usize>,
+    opts: &ReadOptions,
+    table: &'a AnnotateTable,
+) -> Option<&'a [String]> {
+    let found = line_netblocks(line, csv_column, opts);
+    let v4 = found.v4.iter().filter_map(|nb| table.v4.longest_match(nb));
+    let v4 = v4.map(|(_, labels)| &labels[0]);
+    let v6 = found.v6.iter().filter_map(|nb| table.v6.longest_match(nb));
+    let v6 = v6.map(|(_, labels)| &labels[0]);
+    v4.chain(v6).next().map(|labels| &labels[..])
+}
+
+/// Quote a CSV field when it holds a comma, a quote or a line break.
+fn csv_quote(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
     } else {
-        // Try IPv4 CIDR / bare address first
-        if accept_v4
-            && let Ok(nb) = line.parse::<NetblockV4>()
-            && (!ignore_invalid || nb.is_canonical())
-        {
-            v4.push(NetblockV4::new(nb.network, nb.prefix_len));
-            return true;
-        }
-        if accept_v6
-            && let Ok(nb) = line.parse::<NetblockV6>()
-            && (!ignore_invalid || nb.is_canonical())
-        {
-            v6.push(NetblockV6::new(nb.network, nb.prefix_len));
-            return true;
-        }
-        false
+        Cow::Borrowed(field)
     }
 }
 
-/// Read netblocks from a `BufRead` source, tracking line counts.
-fn read_netblocks(
-    reader: &mut dyn BufRead,
-    input_range: bool,
-    ignore_invalid: bool,
-    accept_v4: bool,
-    accept_v6: bool,
-    max_length: Option<u8>,
-    field_opts: Option<&FieldOptions>,
-) -> Result<ParseResult, Box<dyn Error>> {
-    let mut result = ParseResult {
-        v4: Vec::new(),
-        v6: Vec::new(),
-        total_lines: 0,
-        invalid_lines: 0,
-        utf8_invalid_lines: 0,
-    };
+/// Stream every input to `out` with the labels of its match (or
+/// `placeholder` for each label column) appended after `sep`.  With
+/// --csv-field-name the header line of each input names the column and
+/// gets the label column names appended.
+fn annotate_inputs(
+    sources: &[String],
+    table: &AnnotateTable,
+    opts: &ReadOptions,
+    sep: &str,
+    placeholder: &str,
+    out: &mut impl Write,
+) -> Result<AnnotateCounts, Box<dyn Error>> {
+    let mut counts = AnnotateCounts::default();
     let mut buf = Vec::new();
-
-    // Read lines as raw bytes to handle non-UTF8 content
-    loop {
-        buf.clear();
-        let bytes_read = reader.read_until(b'\n', &mut buf)?;
-        if bytes_read == 0 {
-            break; // End of input
-        }
-
-        if let Some(fopts) = field_opts {
-            // Delimiter/fields mode: strict UTF-8 validation
-            let line_str = match std::str::from_utf8(&buf) {
-                Ok(s) => s,
-                Err(_) => {
-                    // Invalid UTF-8: skip and count as utf8 error
-                    result.utf8_invalid_lines += 1;
-                    continue;
-                }
-            };
-            let line = line_str.trim();
-            if line.is_empty() {
-                continue;
+    let scan = opts.fields.is_none() && opts.regex.is_none() && opts.csv.is_none();
+    let placeholders = vec![placeholder.to_string(); table.names.len()];
+    for path in sources {
+        let input = open_input(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut reader = io::BufReader::new(input);
+        let mut csv_column = match opts.csv {
+            Some(CsvOptions::ByNumber(index)) => Some(index),
+            _ => None,
+        };
+        let mut csv_header = match opts.csv {
+            Some(CsvOptions::ByName(ref name)) => Some(name),
+            _ => None,
+        };
+        loop {
+            buf.clear();
+            let bytes_read =
+                reader.read_until(b'\n', &mut buf).map_err(|e| format!("{}: {}", path, e))?;
+            if bytes_read == 0 {
+                break;
             }
-            result.total_lines += 1;
-
-            let parts: Vec<&str> = line.split(fopts.delimiter).collect();
-            let mut any_parsed = false;
-
-            for &field_num in &fopts.fields {
-                if let Some(field_val) = extract_field(&parts, field_num) {
-                    let trimmed = field_val.trim();
-                    if !trimmed.is_empty() {
-                        let parsed = process_line(
-                            trimmed,
-                            input_range,
-                            ignore_invalid,
-                            accept_v4,
-                            accept_v6,
-                            &mut result.v4,
-                            &mut result.v6,
-                        );
-                        if parsed {
-                            any_parsed = true;
-                        }
-                    }
+            // The line is written back as read; the lossy text is only matched
+            let end = buf.iter().rposition(|&b| b != b'\r' && b != b'\n').map_or(0, |i| i + 1);
+            let line = String::from_utf8_lossy(&buf[..end]);
+            let labels = if let Some(name) = csv_header.take() {
+                let header = csv_record(&line).unwrap_or_default();
+                csv_column =
+                    Some(csv_header_index(&header, name).map_err(|e| format!("{}: {}", path, e))?);
+                &table.names[..]
+            } else {
+                counts.lines += 1;
+                // Extraction needs UTF-8; scanning takes what it can find
+                let found = match std::str::from_utf8(&buf) {
+                    Err(_) if !scan => None,
+                    _ => annotate_match(&line, csv_column, opts, table),
+                };
+                if found.is_some() {
+                    counts.annotated += 1;
                 }
+                found.unwrap_or(&placeholders)
+            };
+            out.write_all(&buf[..end])?;
+            for label in labels {
+                let label = if opts.csv.is_some() { csv_quote(label) } else { Cow::from(label) };
+                write!(out, "{}{}", sep, label)?;
             }
+            writeln!(out)?;
+        }
+    }
+    Ok(counts)
+}
 
-            if !any_parsed {
-                result.invalid_lines += 1;
-            }
-        } else {
-            // Original mode: no delimiter/fields, tolerates invalid UTF-8
+// ---------------------------------------------------------------------------
+// Grouped aggregation (--group-by-field)
+// ---------------------------------------------------------------------------
 
-            // Try to convert to string, skip if invalid UTF-8
-            if let Ok(line_str) = std::str::from_utf8(&buf) {
-                let line = line_str.trim();
-                if !line.is_empty() {
-                    result.total_lines += 1;
-                    let parsed = process_line(
-                        line,
-                        input_range,
-                        ignore_invalid,
-                        accept_v4,
-                        accept_v6,
-                        &mut result.v4,
-                        &mut result.v6,
-                    );
-                    if !parsed {
-                        result.invalid_lines += 1;
-                    }
-                }
-            }
-            // If invalid UTF-8, just continue to the next line
+/// The netblocks of one --group-by-field group.
+#[derive(Default)]
+struct Group {
+    v4: Vec<NetblockV4>,
+    v6: Vec<NetblockV6>,
+}
+
+/// Sort the netblocks of `parsed` into their groups, ordered by key.
+fn bucket_groups(parsed: ParseResult) -> BTreeMap<Rc<str>, Group> {
+    let mut groups: BTreeMap<Rc<str>, Group> = BTreeMap::new();
+    for (nb, key) in parsed.v4.into_iter().zip(parsed.groups_v4) {
+        groups.entry(key).or_default().v4.push(nb);
+    }
+    for (nb, key) in parsed.v6.into_iter().zip(parsed.groups_v6) {
+        groups.entry(key).or_default().v6.push(nb);
+    }
+    groups
+}
+
+/// File name for the group `key` in --group-output-dir: characters other
+/// than ASCII letters, digits, '-', '_' and '.' become '_', and names made
+/// of dots only get a leading '_'.
+fn group_file_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .collect();
+    if name.chars().all(|c| c == '.') { format!("_{}", name) } else { name }
+}
+
+/// Write every group to its own file in `dir`.  Keys whose file names
+/// would clash (also ignoring case, for case-insensitive file systems) are
+/// an error, reported before any file is written.
+fn write_group_files(
+    dir: &str,
+    groups: &BTreeMap<Rc<str>, Group>,
+    fmt: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut names: HashMap<String, &str> = HashMap::new();
+    for key in groups.keys() {
+        let name = group_file_name(key);
+        if let Some(other) = names.insert(name.to_ascii_lowercase(), key) {
+            return Err(format!(
+                "--group-output-dir: groups {:?} and {:?} would both be written to {:?}",
+                other, key, name
+            )
+            .into());
         }
     }
+    std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir, e))?;
+    for (key, group) in groups {
+        let path = Path::new(dir).join(group_file_name(key));
+        let file =
+            std::fs::File::create(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut out = io::BufWriter::new(file);
+        write_netblocks(&group.v4, fmt, &mut out);
+        write_netblocks(&group.v6, fmt, &mut out);
+        out.flush().map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
 
-    // Apply max-length clamping if requested
-    if let Some(max_len) = max_length {
-        apply_max_length_v4(&mut result.v4, max_len);
-        apply_max_length_v6(&mut result.v6, max_len);
+/// Write every group as "KEY PREFIX" lines.
+fn write_groups(groups: &BTreeMap<Rc<str>, Group>, fmt: OutputFormat, out: &mut impl Write) {
+    for (key, group) in groups {
+        for line in format_blocks(&group.v4, fmt).iter().chain(&format_blocks(&group.v6, fmt)) {
+            let _ = writeln!(out, "{} {}", key, line);
+        }
     }
+}
 
-    Ok(result)
+/// `"groups": { KEY: { results4, results6 }, ... }` member.
+fn json_groups(groups: &BTreeMap<Rc<str>, Group>, fmt: OutputFormat) -> String {
+    let members: Vec<String> = groups
+        .iter()
+        .map(|(key, group)| {
+            let inner = vec![
+                json_array_member("results4", &format_blocks(&group.v4, fmt), 3),
+                json_array_member("results6", &format_blocks(&group.v6, fmt), 3),
+            ];
+            json_object_member(key, &inner, 2)
+        })
+        .collect();
+    json_object_member("groups", &members, 1)
 }
 
-/// Open a file and read netblocks from it.
-fn read_netblocks_from_file(
-    path: &str,
-    input_range: bool,
-    ignore_invalid: bool,
-    accept_v4: bool,
-    accept_v6: bool,
-    max_length: Option<u8>,
-    field_opts: Option<&FieldOptions>,
-) -> Result<ParseResult, Box<dyn Error>> {
-    let mut reader = io::BufReader::new(std::fs::File::open(path)?);
-    read_netblocks(
-        &mut reader,
-        input_range,
-        ignore_invalid,
-        accept_v4,
-        accept_v6,
-        max_length,
-        field_opts,
-    )
+// ---------------------------------------------------------------------------
+// Density promotion (--promote)
+// ---------------------------------------------------------------------------
+
+/// Units counted by --promote: IPv4 hosts and IPv6 /64 subnets.
+const PROMOTE_UNIT_V4: u8 = 32;
+const PROMOTE_UNIT_V6: u8 = 64;
+
+/// One --promote level.
+struct PromoteSpec {
+    v6: bool,
+    prefix_len: u8,
+    threshold: PromoteThreshold,
+}
+
+/// Parse a --promote specification "[4/|6/]LEN:THRESHOLD", where THRESHOLD
+/// is a unit count ("16") or a percentage ("25%").  Without a family tag the
+/// level applies to IPv6 if `default_v6`, else to IPv4.
+fn parse_promote_spec(s: &str, default_v6: bool) -> Result<PromoteSpec, String> {
+    let (prefix, threshold) =
+        s.split_once(':').ok_or_else(|| format!("expected LEN:THRESHOLD, got {:?}", s))?;
+    let (v6, len_str) = if let Some(rest) = prefix.strip_prefix("4/") {
+        (false, rest)
+    } else if let Some(rest) = prefix.strip_prefix("6/") {
+        (true, rest)
+    } else {
+        (default_v6, prefix.strip_prefix('/').unwrap_or(prefix))
+    };
+
+    let unit = if v6 { PROMOTE_UNIT_V6 } else { PROMOTE_UNIT_V4 };
+    let prefix_len: u8 =
+        len_str.parse().ok().filter(|n| (1..=unit).contains(n)).ok_or_else(|| {
+            format!(
+                "prefix length must be 1-{} for IPv{}, got {:?}",
+                unit,
+                if v6 { 6 } else { 4 },
+                len_str
+            )
+        })?;
+
+    let units = 1u128 << (unit - prefix_len);
+    let threshold = if let Some(pct) = threshold.strip_suffix('%') {
+        let p: f64 = pct.parse().map_err(|_| format!("invalid percentage: {:?}", pct))?;
+        if !(p > 0.0 && p <= 100.0) {
+            return Err(format!("percentage must be above 0 and at most 100, got {}", pct));
+        }
+        PromoteThreshold::Percent(p)
+    } else {
+        let k: u128 = threshold.parse().map_err(|_| format!("invalid count: {:?}", threshold))?;
+        if k == 0 || k > units {
+            return Err(format!("count for a /{} must be 1-{}, got {}", prefix_len, units, k));
+        }
+        PromoteThreshold::Count(k)
+    };
+    Ok(PromoteSpec { v6, prefix_len, threshold })
+}
+
+/// Apply the --promote levels of one family, longest prefix first so that
+/// blocks promoted at one level count towards the next.
+fn apply_promotions<T: Aggregateable>(
+    mut blocks: Vec<T>,
+    specs: &[&PromoteSpec],
+    unit_len: u8,
+) -> (Vec<T>, Vec<Promotion<T>>) {
+    let mut specs = specs.to_vec();
+    specs.sort_by_key(|spec| Reverse(spec.prefix_len));
+    let mut promotions = Vec::new();
+    for spec in specs {
+        let (promoted, added) = promote_dense(blocks, spec.prefix_len, unit_len, spec.threshold);
+        blocks = promoted;
+        promotions.extend(added);
+    }
+    (blocks, promotions)
+}
+
+/// Total addresses added by promotions.  Each level only counts what the
+/// levels before it left uncovered, so the sum is exact.
+fn promoted_addresses<T: Aggregateable>(promotions: &[Promotion<T>]) -> u128 {
+    promotions.iter().map(|p| p.added).sum()
+}
+
+/// Write the --stats report of one family's promotions.
+fn write_promotions<T: Aggregateable>(
+    family: &str,
+    promotions: &[Promotion<T>],
+    out: &mut impl Write,
+) {
+    let _ = writeln!(
+        out,
+        "{} promoted: {} blocks (+{} addresses)",
+        family,
+        promotions.len(),
+        promoted_addresses(promotions)
+    );
+    for p in promotions {
+        let _ = writeln!(
+            out,
+            "  {}: {} of {} listed (+{} addresses)",
+            p.prefix, p.listed, p.units, p.added
+        );
+    }
+}
+
+/// JSON stats members (indent 3) describing one family's promotions.
+fn json_promotions<T: Aggregateable>(promotions: &[Promotion<T>]) -> Vec<String> {
+    let objects: Vec<Vec<String>> = promotions
+        .iter()
+        .map(|p| {
+            vec![
+                json_str_member("prefix", &p.prefix.to_string(), 5),
+                json_num_member("listed", p.listed as u64, 5),
+                json_num_member("units", p.units as u64, 5),
+                json_str_member("added", &p.added.to_string(), 5),
+            ]
+        })
+        .collect();
+    vec![
+        json_object_array_member("promoted", &objects, 3),
+        json_str_member("promoted_addresses", &promoted_addresses(promotions).to_string(), 3),
+    ]
 }
 
 // ---------------------------------------------------------------------------
@@ -1412,21 +2882,104 @@ fn main() -> Result<(), Box<dyn Error>> {
         eprintln!("error: --diff requires exactly two input files");
         std::process::exit(1);
     }
-    if cli.diff && cli.output_range {
-        eprintln!("error: --diff cannot be combined with --output-range");
+    if cli.diff && (cli.output_range || cli.output_int_range) {
+        eprintln!("error: --diff cannot be combined with --output-range or --output-int-range");
         std::process::exit(1);
     }
 
     // Validate output formats are mutually exclusive
-    let fmt_count =
-        [cli.output_range, cli.output_netmask, cli.output_wildcard].iter().filter(|&&b| b).count();
+    let fmt_count = [
+        cli.output_range,
+        cli.output_netmask,
+        cli.output_wildcard,
+        cli.output_int_range,
+        cli.output_hex_cidr,
+    ]
+    .iter()
+    .filter(|&&b| b)
+    .count();
     if fmt_count > 1 {
         eprintln!(
-            "error: --output-range, --output-netmask, and --output-wildcard are mutually exclusive"
+            "error: --output-range, --output-netmask, --output-wildcard, --output-int-range \
+             and --output-hex-cidr are mutually exclusive"
+        );
+        std::process::exit(1);
+    }
+
+    if cli.diff && (cli.max_entries.is_some() || !cli.promote.is_empty()) {
+        eprintln!("error: --diff cannot be combined with --max-entries or --promote");
+        std::process::exit(1);
+    }
+
+    // Validate --floor4/--floor6/--no-merge
+    let has_floor = cli.floor4.is_some() || cli.floor6.is_some();
+    if cli.floor4.is_some_and(|n| n > 32) || cli.floor6.is_some_and(|n| n > 128) {
+        eprintln!("error: --floor4 must be at most 32 and --floor6 at most 128");
+        std::process::exit(1);
+    }
+    if cli.no_merge && has_floor {
+        eprintln!("error: --no-merge cannot be combined with --floor4/--floor6");
+        std::process::exit(1);
+    }
+    if (cli.no_merge || has_floor) && (cli.diff || cli.max_entries.is_some()) {
+        eprintln!(
+            "error: --no-merge and --floor4/--floor6 cannot be combined with --diff or --max-entries"
         );
         std::process::exit(1);
     }
 
+    // Validate --min-length
+    if let Some(min) = cli.min_length {
+        if min > 128 {
+            eprintln!("error: --min-length must be at most 128");
+            std::process::exit(1);
+        }
+        if cli.max_length.is_some_and(|max| min > max) {
+            eprintln!("error: --min-length cannot be greater than --max-length");
+            std::process::exit(1);
+        }
+        if cli.diff || cli.max_entries.is_some() {
+            eprintln!("error: --min-length cannot be combined with --diff or --max-entries");
+            std::process::exit(1);
+        }
+    }
+    if cli.max_entries == Some(0) {
+        eprintln!("error: --max-entries must be at least 1");
+        std::process::exit(1);
+    }
+
+    // Parse --promote levels
+    let default_promote_v6 = cli.ipv6_only && !cli.ipv4_only;
+    let mut promote_specs = Vec::with_capacity(cli.promote.len());
+    for spec in &cli.promote {
+        match parse_promote_spec(spec, default_promote_v6) {
+            Ok(spec) => promote_specs.push(spec),
+            Err(e) => {
+                eprintln!("error: invalid --promote {:?}: {}", spec, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let promote_v4: Vec<&PromoteSpec> = promote_specs.iter().filter(|s| !s.v6).collect();
+    let promote_v6: Vec<&PromoteSpec> = promote_specs.iter().filter(|s| s.v6).collect();
+
+    // Validate invalid-line limits
+    if cli.strict && (cli.max_invalid.is_some() || cli.max_invalid_percent.is_some()) {
+        eprintln!("error: --strict cannot be combined with --max-invalid/--max-invalid-percent");
+        std::process::exit(1);
+    }
+    if let Some(p) = cli.max_invalid_percent
+        && !(0.0..=100.0).contains(&p)
+    {
+        eprintln!("error: --max-invalid-percent must be between 0 and 100");
+        std::process::exit(1);
+    }
+
+    if cli.input_range && cli.input_auto {
+        eprintln!("error: --input-range cannot be combined with --input-auto");
+        std::process::exit(1);
+    }
+
     // Validate --delimiter and --fields must be used together
     if cli.delimiter.is_some() != cli.fields.is_some() {
         eprintln!("error: --delimiter and --fields must be specified together");
@@ -1449,6 +3002,34 @@ fn main() -> Result<(), Box<dyn Error>> {
         std::process::exit(1);
     }
 
+    // Validate comment markers and --dialect
+    if cli.comment.iter().any(|m| m.is_empty() || m.contains(char::is_whitespace)) {
+        eprintln!("error: --comment markers must be non-empty and contain no whitespace");
+        std::process::exit(1);
+    }
+    if has_csv && (cli.dialect.is_some() || !cli.comment.is_empty()) {
+        eprintln!(
+            "error: --comment/--dialect cannot be combined with --csv-field-number/--csv-field-name"
+        );
+        std::process::exit(1);
+    }
+    if has_delim && cli.dialect.is_some() {
+        eprintln!("error: --dialect cannot be combined with --delimiter/--fields");
+        std::process::exit(1);
+    }
+    if cli.keep_comments && cli.comment.is_empty() && cli.dialect.is_none() {
+        eprintln!("error: --keep-comments requires --comment or --dialect");
+        std::process::exit(1);
+    }
+    if cli.diff && (cli.keep_comments || cli.explain) {
+        eprintln!("error: --keep-comments and --explain cannot be combined with --diff");
+        std::process::exit(1);
+    }
+    if cli.max_comments.is_some() && !cli.keep_comments {
+        eprintln!("error: --max-comments requires --keep-comments");
+        std::process::exit(1);
+    }
+
     // Validate --csv-field-number is >= 1 (1-based)
     if let Some(n) = cli.csv_field_number
         && n < 1
@@ -1479,6 +3060,135 @@ fn main() -> Result<(), Box<dyn Error>> {
         _ => None,
     };
 
+    if cli.scan
+        && (has_csv
+            || has_delim
+            || cli.regex.is_some()
+            || cli.dialect.is_some()
+            || cli.input_range
+            || cli.input_auto
+            || cli.input_nmap
+            || cli.input_int)
+    {
+        eprintln!(
+            "error: --scan cannot be combined with --delimiter/--fields, the CSV options, \
+             --regex, --dialect or the --input-* modes"
+        );
+        std::process::exit(1);
+    }
+
+    // Validate --grep
+    if cli.invert_match && cli.grep.is_none() {
+        eprintln!("error: -v/--invert-match requires --grep");
+        std::process::exit(1);
+    }
+    if cli.grep.is_some()
+        && (cli.diff
+            || cli.json
+            || cli.exclude.is_some()
+            || cli.intersect.is_some()
+            || cli.keep_comments
+            || cli.explain)
+    {
+        eprintln!(
+            "error: --grep cannot be combined with --diff, --json, --exclude, --intersect, \
+             --keep-comments or --explain"
+        );
+        std::process::exit(1);
+    }
+
+    // Validate --lookup
+    if cli.lookup_all && cli.lookup.is_none() {
+        eprintln!("error: --lookup-all requires --lookup");
+        std::process::exit(EXIT_LOOKUP_ERROR);
+    }
+    if cli.lookup.is_some()
+        && (cli.diff
+            || cli.grep.is_some()
+            || cli.exclude.is_some()
+            || cli.intersect.is_some()
+            || cli.keep_comments
+            || cli.explain
+            || cli.scan)
+    {
+        eprintln!(
+            "error: --lookup cannot be combined with --diff, --grep, --exclude, --intersect, \
+             --keep-comments, --explain or --scan"
+        );
+        std::process::exit(EXIT_LOOKUP_ERROR);
+    }
+
+    // Validate --annotate
+    let table_opts = cli.table_field_number.is_some()
+        || cli.table_field_name.is_some()
+        || !cli.table_label_numbers.is_empty()
+        || !cli.table_label_names.is_empty();
+    if table_opts && cli.annotate.is_none() {
+        eprintln!(
+            "error: --table-field-number/--table-field-name/--table-label-numbers/\
+             --table-label-names require --annotate"
+        );
+        std::process::exit(1);
+    }
+    if cli.table_field_number.is_some() && cli.table_field_name.is_some() {
+        eprintln!("error: --table-field-number and --table-field-name cannot be used together");
+        std::process::exit(1);
+    }
+    if !cli.table_label_numbers.is_empty() && !cli.table_label_names.is_empty() {
+        eprintln!("error: --table-label-numbers and --table-label-names cannot be used together");
+        std::process::exit(1);
+    }
+    if cli.table_field_number == Some(0) || cli.table_label_numbers.contains(&0) {
+        eprintln!("error: --table-field-number/--table-label-numbers are 1-based, 0 is not valid");
+        std::process::exit(1);
+    }
+    if cli.annotate.is_some()
+        && (cli.diff
+            || cli.json
+            || cli.grep.is_some()
+            || cli.lookup.is_some()
+            || cli.exclude.is_some()
+            || cli.intersect.is_some()
+            || cli.keep_comments
+            || cli.explain)
+    {
+        eprintln!(
+            "error: --annotate cannot be combined with --diff, --json, --grep, --lookup, \
+             --exclude, --intersect, --keep-comments or --explain"
+        );
+        std::process::exit(1);
+    }
+
+    // Validate and compile --regex
+    if cli.regex_groups.is_some() && cli.regex.is_none() {
+        eprintln!("error: --regex-groups requires --regex");
+        std::process::exit(1);
+    }
+    if cli.regex.is_some() && (has_csv || has_delim || cli.dialect.is_some()) {
+        eprintln!(
+            "error: --regex cannot be combined with --delimiter/--fields, \
+             --csv-field-number/--csv-field-name or --dialect"
+        );
+        std::process::exit(1);
+    }
+    let regex_opts = cli.regex.as_ref().map(|pattern| {
+        let regex = match Regex::new(pattern) {
+            Ok(re) => re,
+            Err(e) => {
+                eprintln!("error: invalid --regex: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let groups = match parse_regex_groups(&regex, cli.regex_groups.as_deref()) {
+            Ok(groups) => groups,
+            Err(e) => {
+                eprintln!("error: invalid --regex-groups: {}", e);
+                std::process::exit(1);
+            }
+        };
+        RegexOptions { regex, groups }
+    });
+
     // Parse CSV options
     let csv_opts = if let Some(n) = cli.csv_field_number {
         Some(CsvOptions::ByNumber(n - 1)) // Convert 1-based to 0-based
@@ -1486,9 +3196,107 @@ fn main() -> Result<(), Box<dyn Error>> {
         cli.csv_field_name.as_ref().map(|name| CsvOptions::ByName(name.clone()))
     };
 
+    // Validate and parse --group-by-field
+    if cli.group_output_dir.is_some() && cli.group_by_field.is_none() {
+        eprintln!("error: --group-output-dir requires --group-by-field");
+        std::process::exit(1);
+    }
+    if cli.group_by_field.is_some() && !has_csv && field_opts.is_none() {
+        eprintln!(
+            "error: --group-by-field requires --delimiter/--fields or \
+             --csv-field-number/--csv-field-name"
+        );
+        std::process::exit(1);
+    }
+    if cli.group_by_field.is_some()
+        && (cli.diff
+            || cli.grep.is_some()
+            || cli.lookup.is_some()
+            || cli.annotate.is_some()
+            || cli.exclude.is_some()
+            || cli.intersect.is_some()
+            || cli.keep_comments
+            || cli.explain
+            || cli.max_entries.is_some()
+            || !cli.promote.is_empty())
+    {
+        eprintln!(
+            "error: --group-by-field cannot be combined with --diff, --grep, --lookup, \
+             --annotate, --exclude, --intersect, --keep-comments, --explain, --max-entries \
+             or --promote"
+        );
+        std::process::exit(1);
+    }
+    if cli.group_output_dir.is_some() && cli.json {
+        eprintln!("error: --group-output-dir cannot be combined with --json");
+        std::process::exit(1);
+    }
+    let group_by = cli.group_by_field.as_ref().map(|spec| {
+        let parsed = if cli.csv_field_name.is_some() {
+            Ok(GroupField::Csv(CsvOptions::ByName(spec.clone())))
+        } else if has_csv {
+            match spec.trim().parse::<usize>() {
+                Ok(n) if n >= 1 => Ok(GroupField::Csv(CsvOptions::ByNumber(n - 1))),
+                _ => Err(format!("invalid column number: {:?}", spec)),
+            }
+        } else {
+            match parse_field_spec(spec) {
+                Ok(fields) if fields.len() == 1 => Ok(GroupField::Field(fields[0])),
+                Ok(_) => Err("exactly one field number is required".to_string()),
+                Err(e) => Err(e),
+            }
+        };
+        parsed.unwrap_or_else(|e| {
+            eprintln!("error: invalid --group-by-field {:?}: {}", spec, e);
+            std::process::exit(1);
+        })
+    });
+
     let accept_v4 = cli.accept_v4();
     let accept_v6 = cli.accept_v6();
 
+    let mut comment_markers = cli.comment.clone();
+    if let Some(dialect) = cli.dialect {
+        comment_markers.extend(dialect.comment_markers().iter().map(|m| m.to_string()));
+    }
+
+    let read_opts = ReadOptions {
+        input_mode: if cli.input_auto {
+            InputMode::Auto
+        } else if cli.input_range {
+            InputMode::Range
+        } else {
+            InputMode::Cidr
+        },
+        ignore_invalid: cli.ignore_invalid,
+        accept_v4,
+        accept_v6,
+        max_length: cli.max_length,
+        expand_wildcards: cli.expand_wildcards,
+        expand_limit: cli.expand_limit,
+        nmap: cli.input_nmap,
+        int: cli.input_int,
+        scan: cli.scan,
+        comment_markers,
+        column: cli.dialect.and_then(Dialect::column),
+        keep_comments: cli.keep_comments,
+        explain: cli.explain,
+        fields: field_opts,
+        regex: regex_opts,
+        csv: csv_opts,
+        group_by,
+    };
+
+    let limits = InvalidLimits {
+        max_count: if cli.strict { Some(0) } else { cli.max_invalid },
+        max_percent: cli.max_invalid_percent,
+    };
+
+    let mut rejects = match cli.rejects {
+        Some(ref path) => RejectSink::create(path)?,
+        None => RejectSink::default(),
+    };
+
     // Resolve output format (flags already validated as mutually exclusive).
     let out_fmt = if cli.output_range {
         OutputFormat::Range
@@ -1496,6 +3304,10 @@ fn main() -> Result<(), Box<dyn Error>> {
         OutputFormat::Netmask
     } else if cli.output_wildcard {
         OutputFormat::Wildcard
+    } else if cli.output_int_range {
+        OutputFormat::IntRange
+    } else if cli.output_hex_cidr {
+        OutputFormat::HexCidr
     } else {
         OutputFormat::Cidr
     };
@@ -1504,48 +3316,11 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Diff mode — compare two files
     // -----------------------------------------------------------------------
     if cli.diff {
-        let old = if let Some(ref copts) = csv_opts {
-            read_netblocks_csv_from_file(
-                &cli.input[0],
-                copts,
-                cli.input_range,
-                cli.ignore_invalid,
-                accept_v4,
-                accept_v6,
-                cli.max_length,
-            )?
-        } else {
-            read_netblocks_from_file(
-                &cli.input[0],
-                cli.input_range,
-                cli.ignore_invalid,
-                accept_v4,
-                accept_v6,
-                cli.max_length,
-                field_opts.as_ref(),
-            )?
-        };
-        let new = if let Some(ref copts) = csv_opts {
-            read_netblocks_csv_from_file(
-                &cli.input[1],
-                copts,
-                cli.input_range,
-                cli.ignore_invalid,
-                accept_v4,
-                accept_v6,
-                cli.max_length,
-            )?
-        } else {
-            read_netblocks_from_file(
-                &cli.input[1],
-                cli.input_range,
-                cli.ignore_invalid,
-                accept_v4,
-                accept_v6,
-                cli.max_length,
-                field_opts.as_ref(),
-            )?
-        };
+        let old = read_source(&cli.input[0], &read_opts, &mut rejects)?;
+        let new = read_source(&cli.input[1], &read_opts, &mut rejects)?;
+        limits.enforce(&format!("--diff {}", cli.input[0]), &old, &mut rejects);
+        limits.enforce(&format!("--diff {}", cli.input[1]), &new, &mut rejects);
+        rejects.flush()?;
 
         let old_v4 = normalize_netblocks(old.v4);
         let new_v4 = normalize_netblocks(new.v4);
@@ -1629,71 +3404,218 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
 
     // -----------------------------------------------------------------------
-    // Normal mode — aggregate (with optional exclude / intersect)
+    // Grep mode — filter text lines against a set
     // -----------------------------------------------------------------------
+    if let Some(ref set_path) = cli.grep {
+        // The set file is a plain list; extraction applies to the text only
+        let set_opts =
+            ReadOptions { fields: None, regex: None, csv: None, scan: false, ..read_opts.clone() };
+        let set = read_source(set_path, &set_opts, &mut rejects)?;
+        limits.enforce(&format!("--grep {}", set_path), &set, &mut rejects);
+        rejects.flush()?;
+        let set = GrepSet { v4: IpSet4::from_vec(set.v4), v6: IpSet6::from_vec(set.v6) };
+
+        let sources = expand_inputs(&cli.input)?;
+        let mut stdout = io::BufWriter::new(io::stdout().lock());
+        let counts = grep_inputs(&sources, &set, &read_opts, cli.invert_match, &mut stdout)?;
+        stdout.flush()?;
 
-    // Create the reader based on input arg
-    let parsed = if let Some(ref copts) = csv_opts {
-        let input: Box<dyn io::Read> = if let Some(file) = cli.input.first() {
-            Box::new(std::fs::File::open(file)?)
-        } else {
-            Box::new(io::stdin())
+        if cli.stats {
+            let mut stderr = io::stderr().lock();
+            let _ =
+                writeln!(stderr, "Set: {} IPv4 and {} IPv6 prefixes", set.v4.len(), set.v6.len());
+            let _ = writeln!(stderr, "Lines: {}  Selected: {}", counts.lines, counts.selected);
+        }
+        return Ok(());
+    }
+
+    // -----------------------------------------------------------------------
+    // Annotate mode — join text lines against a labelled prefix table
+    // -----------------------------------------------------------------------
+    if let Some(ref table_path) = cli.annotate {
+        let prefix = match cli.table_field_name {
+            Some(ref name) => CsvOptions::ByName(name.clone()),
+            None => CsvOptions::ByNumber(cli.table_field_number.unwrap_or(1) - 1),
         };
-        read_netblocks_csv(
-            input,
-            copts,
-            cli.input_range,
-            cli.ignore_invalid,
-            accept_v4,
-            accept_v6,
-            cli.max_length,
-        )?
-    } else {
-        let mut input: Box<dyn BufRead> = if let Some(file) = cli.input.first() {
-            Box::new(io::BufReader::new(std::fs::File::open(file)?))
+        let labels = if cli.table_label_names.is_empty() {
+            cli.table_label_numbers.iter().map(|n| CsvOptions::ByNumber(n - 1)).collect()
         } else {
-            Box::new(io::BufReader::new(io::stdin()))
+            cli.table_label_names.iter().map(|name| CsvOptions::ByName(name.clone())).collect()
         };
-        read_netblocks(
-            &mut input,
-            cli.input_range,
-            cli.ignore_invalid,
-            accept_v4,
-            accept_v6,
-            cli.max_length,
-            field_opts.as_ref(),
-        )?
-    };
+        let columns = TableColumns { prefix, labels };
+        // The table's prefix column is a plain list; extraction applies to the text only
+        let table_opts =
+            ReadOptions { fields: None, regex: None, csv: None, scan: false, ..read_opts.clone() };
+        let (table, parsed) = read_annotate_table(table_path, &columns, &table_opts, &mut rejects)?;
+        limits.enforce(&format!("--annotate {}", table_path), &parsed, &mut rejects);
+        rejects.flush()?;
+
+        let sep = match read_opts.fields {
+            Some(ref fopts) => fopts.delimiter.to_string(),
+            None if read_opts.csv.is_some() => ",".to_string(),
+            None => " ".to_string(),
+        };
+        let sources = expand_inputs(&cli.input)?;
+        let mut stdout = io::BufWriter::new(io::stdout().lock());
+        let counts = annotate_inputs(
+            &sources,
+            &table,
+            &read_opts,
+            &sep,
+            &cli.annotate_placeholder,
+            &mut stdout,
+        )?;
+        stdout.flush()?;
+
+        if cli.stats {
+            let mut stderr = io::stderr().lock();
+            let _ = writeln!(
+                stderr,
+                "Table: {} IPv4 and {} IPv6 prefixes",
+                table.v4.len(),
+                table.v6.len()
+            );
+            let _ = writeln!(stderr, "Lines: {}  Annotated: {}", counts.lines, counts.annotated);
+        }
+        return Ok(());
+    }
+
+    // -----------------------------------------------------------------------
+    // Lookup mode — longest-prefix queries against a set
+    // -----------------------------------------------------------------------
+    if let Some(ref set_path) = cli.lookup {
+        let status = match run_lookups(set_path, &cli, &read_opts, &limits, &mut rejects, out_fmt) {
+            Ok(status) => status,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                EXIT_LOOKUP_ERROR
+            }
+        };
+        std::process::exit(status);
+    }
+
+    // -----------------------------------------------------------------------
+    // Group mode — aggregate every --group-by-field group separately
+    // -----------------------------------------------------------------------
+    if read_opts.group_by.is_some() {
+        let sources = expand_inputs(&cli.input)?;
+        let mut parsed = ParseResult::default();
+        for path in &sources {
+            parsed.merge(read_source(path, &read_opts, &mut rejects)?);
+        }
+        limits.enforce("input", &parsed, &mut rejects);
+        rejects.flush()?;
+
+        let (lines, invalid, utf8_invalid) =
+            (parsed.total_lines, parsed.invalid_lines, parsed.utf8_invalid_lines);
+        let (v4_before, v6_before) = (parsed.v4.len(), parsed.v6.len());
+        let mut groups = bucket_groups(parsed);
+        for group in groups.values_mut() {
+            let Group { v4, v6 } = std::mem::take(group);
+            let (mut v4, mut v6) = if cli.no_merge {
+                (normalize_netblocks(v4), normalize_netblocks(v6))
+            } else {
+                (
+                    aggregate_netblocks_floor(v4, cli.floor4.unwrap_or(0)),
+                    aggregate_netblocks_floor(v6, cli.floor6.unwrap_or(0)),
+                )
+            };
+            if let Some(min) = cli.min_length {
+                if min <= 32 {
+                    v4 = split_output(&v4, min, cli.split_limit, "IPv4");
+                }
+                v6 = split_output(&v6, min, cli.split_limit, "IPv6");
+            }
+            *group = Group { v4, v6 };
+        }
+        let v4_after: usize = groups.values().map(|g| g.v4.len()).sum();
+        let v6_after: usize = groups.values().map(|g| g.v6.len()).sum();
+
+        let mut stdout = io::stdout().lock();
+        if let Some(ref dir) = cli.group_output_dir {
+            write_group_files(dir, &groups, out_fmt)?;
+        } else if cli.json {
+            let mut members = vec![json_groups(&groups, out_fmt)];
+            if cli.stats {
+                let stats_inner = vec![
+                    json_num_member("lines", lines as u64, 2),
+                    json_num_member("invalid", invalid as u64, 2),
+                    json_num_member("utf8_errors", utf8_invalid as u64, 2),
+                    json_num_member("groups", groups.len() as u64, 2),
+                ];
+                members.push(json_object_member("stats", &stats_inner, 1));
+            }
+            let _ = write!(stdout, "{}", json_document(&members));
+            return Ok(());
+        } else {
+            write_groups(&groups, out_fmt, &mut stdout);
+        }
+
+        if cli.stats {
+            let mut stderr = io::stderr().lock();
+            let _ = writeln!(
+                stderr,
+                "Lines: {}  Invalid: {}  UTF-8 errors: {}",
+                lines, invalid, utf8_invalid
+            );
+            let _ = writeln!(
+                stderr,
+                "Groups: {}  IPv4: {} -> {} aggregated  IPv6: {} -> {} aggregated",
+                groups.len(),
+                v4_before,
+                v4_after,
+                v6_before,
+                v6_after
+            );
+        }
+        return Ok(());
+    }
+
+    // -----------------------------------------------------------------------
+    // Normal mode — aggregate (with optional exclude / intersect)
+    // -----------------------------------------------------------------------
+
+    // Read every input source and union the results
+    let sources = expand_inputs(&cli.input)?;
+    let mut parsed = ParseResult::default();
+    let mut source_stats = Vec::with_capacity(sources.len());
+    for path in &sources {
+        let part = read_source(path, &read_opts, &mut rejects)?;
+        source_stats.push(SourceStats::new(path, &part));
+        parsed.merge(part);
+    }
+    limits.enforce("input", &parsed, &mut rejects);
 
     let v4_before = parsed.v4.len();
     let v6_before = parsed.v6.len();
 
-    let mut result_v4 = aggregate_netblocks(parsed.v4);
-    let mut result_v6 = aggregate_netblocks(parsed.v6);
+    // Apply --promote before aggregation, so promoted blocks merge normally
+    let mut promoted_v4 = None;
+    let mut promoted_v6 = None;
+    if !promote_v4.is_empty() {
+        let (blocks, promotions) = apply_promotions(parsed.v4, &promote_v4, PROMOTE_UNIT_V4);
+        parsed.v4 = blocks;
+        promoted_v4 = Some(promotions);
+    }
+    if !promote_v6.is_empty() {
+        let (blocks, promotions) = apply_promotions(parsed.v6, &promote_v6, PROMOTE_UNIT_V6);
+        parsed.v6 = blocks;
+        promoted_v6 = Some(promotions);
+    }
+
+    let (mut result_v4, mut result_v6) = if cli.no_merge {
+        (normalize_netblocks(parsed.v4), normalize_netblocks(parsed.v6))
+    } else {
+        (
+            aggregate_netblocks_floor(parsed.v4, cli.floor4.unwrap_or(0)),
+            aggregate_netblocks_floor(parsed.v6, cli.floor6.unwrap_or(0)),
+        )
+    };
 
     // Apply --exclude if specified
     if let Some(ref excl_path) = cli.exclude {
-        let excl = if let Some(ref copts) = csv_opts {
-            read_netblocks_csv_from_file(
-                excl_path,
-                copts,
-                cli.input_range,
-                cli.ignore_invalid,
-                accept_v4,
-                accept_v6,
-                cli.max_length,
-            )?
-        } else {
-            read_netblocks_from_file(
-                excl_path,
-                cli.input_range,
-                cli.ignore_invalid,
-                accept_v4,
-                accept_v6,
-                cli.max_length,
-                field_opts.as_ref(),
-            )?
-        };
+        let excl = read_source(excl_path, &read_opts, &mut rejects)?;
+        limits.enforce(&format!("--exclude {}", excl_path), &excl, &mut rejects);
         let excl_v4 = aggregate_netblocks(excl.v4);
         let excl_v6 = aggregate_netblocks(excl.v6);
 
@@ -1707,27 +3629,8 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     // Apply --intersect if specified
     if let Some(ref isect_path) = cli.intersect {
-        let isect = if let Some(ref copts) = csv_opts {
-            read_netblocks_csv_from_file(
-                isect_path,
-                copts,
-                cli.input_range,
-                cli.ignore_invalid,
-                accept_v4,
-                accept_v6,
-                cli.max_length,
-            )?
-        } else {
-            read_netblocks_from_file(
-                isect_path,
-                cli.input_range,
-                cli.ignore_invalid,
-                accept_v4,
-                accept_v6,
-                cli.max_length,
-                field_opts.as_ref(),
-            )?
-        };
+        let isect = read_source(isect_path, &read_opts, &mut rejects)?;
+        limits.enforce(&format!("--intersect {}", isect_path), &isect, &mut rejects);
         let isect_v4 = aggregate_netblocks(isect.v4);
         let isect_v6 = aggregate_netblocks(isect.v6);
 
@@ -1739,18 +3642,69 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    rejects.flush()?;
+
+    // Apply --max-entries last, so the budget holds for what is printed
+    let mut overcovered_v4 = None;
+    let mut overcovered_v6 = None;
+    if let Some(max_entries) = cli.max_entries {
+        let (blocks, over) = aggregate_lossy(result_v4, max_entries);
+        result_v4 = blocks;
+        overcovered_v4 = Some(over);
+        let (blocks, over) = aggregate_lossy(result_v6, max_entries);
+        result_v6 = blocks;
+        overcovered_v6 = Some(over);
+    }
+
+    // Apply --min-length to the final result, refusing runaway expansions
+    if let Some(min) = cli.min_length {
+        if min <= 32 {
+            result_v4 = split_output(&result_v4, min, cli.split_limit, "IPv4");
+        }
+        result_v6 = split_output(&result_v6, min, cli.split_limit, "IPv6");
+    }
+
     // Output aggregated netblocks to stdout
     let mut stdout = io::stdout().lock();
 
+    // With --keep-comments/--explain, report on the inputs behind every line
+    let report = OriginReport {
+        comments: cli.keep_comments,
+        max_comments: cli.max_comments,
+        explain: cli.explain,
+    };
+    let reported = (report.comments || report.explain).then(|| {
+        let v4 = if accept_v4 {
+            report_lines(&result_v4, out_fmt, &parsed.origins_v4, &report)
+        } else {
+            Vec::new()
+        };
+        let v6 = if accept_v6 {
+            report_lines(&result_v6, out_fmt, &parsed.origins_v6, &report)
+        } else {
+            Vec::new()
+        };
+        (v4, v6)
+    });
+
     if cli.json {
         // JSON: results4 / results6 arrays, plus an optional separate stats object.
-        let results4 = if accept_v4 { format_blocks(&result_v4, out_fmt) } else { Vec::new() };
-        let results6 = if accept_v6 { format_blocks(&result_v6, out_fmt) } else { Vec::new() };
-
-        let mut members = vec![
-            json_array_member("results4", &results4, 1),
-            json_array_member("results6", &results6, 1),
-        ];
+        let mut members = if let Some((ref v4, ref v6)) = reported {
+            let objects = |lines: &[ReportedLine]| -> Vec<Vec<String>> {
+                lines.iter().map(json_reported_line).collect()
+            };
+            vec![
+                json_object_array_member("results4", &objects(v4), 1),
+                json_object_array_member("results6", &objects(v6), 1),
+            ]
+        } else {
+            let results4 = if accept_v4 { format_blocks(&result_v4, out_fmt) } else { Vec::new() };
+            let results6 = if accept_v6 { format_blocks(&result_v6, out_fmt) } else { Vec::new() };
+            vec![
+                json_array_member("results4", &results4, 1),
+                json_array_member("results6", &results6, 1),
+            ]
+        };
 
         if cli.stats {
             let mut stats_inner = vec![
@@ -1758,20 +3712,67 @@ fn main() -> Result<(), Box<dyn Error>> {
                 json_num_member("invalid", parsed.invalid_lines as u64, 2),
                 json_num_member("utf8_errors", parsed.utf8_invalid_lines as u64, 2),
             ];
+            let files: Vec<Vec<String>> = source_stats
+                .iter()
+                .map(|st| {
+                    vec![
+                        json_str_member("file", &st.name, 4),
+                        json_num_member("lines", st.total_lines as u64, 4),
+                        json_num_member("invalid", st.invalid_lines as u64, 4),
+                        json_num_member("utf8_errors", st.utf8_invalid_lines as u64, 4),
+                    ]
+                })
+                .collect();
+            stats_inner.push(json_object_array_member("files", &files, 2));
+            if cli.input_auto {
+                let forms = vec![
+                    json_num_member("cidr", parsed.forms.cidr as u64, 3),
+                    json_num_member("address", parsed.forms.address as u64, 3),
+                    json_num_member("mask", parsed.forms.mask as u64, 3),
+                    json_num_member("range", parsed.forms.range as u64, 3),
+                    json_num_member("pattern", parsed.forms.pattern as u64, 3),
+                    json_num_member("integer", parsed.forms.integer as u64, 3),
+                ];
+                stats_inner.push(json_object_member("forms", &forms, 2));
+            }
+            if cli.scan {
+                let scan = vec![
+                    json_num_member("matches", parsed.scan.matches as u64, 3),
+                    json_num_member("lines", parsed.scan.lines as u64, 3),
+                    json_num_member("max_per_line", parsed.scan.max_per_line as u64, 3),
+                ];
+                stats_inner.push(json_object_member("scan", &scan, 2));
+            }
             if accept_v4 {
+                let mut extra = Vec::new();
+                if let Some(ref promotions) = promoted_v4 {
+                    extra.extend(json_promotions(promotions));
+                }
+                if let Some(over) = overcovered_v4 {
+                    extra.push(json_str_member("overcovered", &over.to_string(), 3));
+                }
                 stats_inner.push(json_family_stats(
                     "ipv4",
                     v4_before,
                     result_v4.len(),
                     &total_addresses_string(&result_v4),
+                    extra,
                 ));
             }
             if accept_v6 {
+                let mut extra = Vec::new();
+                if let Some(ref promotions) = promoted_v6 {
+                    extra.extend(json_promotions(promotions));
+                }
+                if let Some(over) = overcovered_v6 {
+                    extra.push(json_str_member("overcovered", &over.to_string(), 3));
+                }
                 stats_inner.push(json_family_stats(
                     "ipv6",
                     v6_before,
                     result_v6.len(),
                     &total_addresses_string(&result_v6),
+                    extra,
                 ));
             }
             members.push(json_object_member("stats", &stats_inner, 1));
@@ -1781,11 +3782,18 @@ fn main() -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
 
-    if accept_v4 {
-        write_netblocks(&result_v4, out_fmt, &mut stdout);
-    }
-    if accept_v6 {
-        write_netblocks(&result_v6, out_fmt, &mut stdout);
+    if let Some((ref v4, ref v6)) = reported {
+        let marker = read_opts.comment_markers.first().map(String::as_str);
+        for line in v4.iter().chain(v6) {
+            write_reported_line(line, marker, &mut stdout);
+        }
+    } else {
+        if accept_v4 {
+            write_netblocks(&result_v4, out_fmt, &mut stdout);
+        }
+        if accept_v6 {
+            write_netblocks(&result_v6, out_fmt, &mut stdout);
+        }
     }
 
     // Print statistics to stderr if requested
@@ -1796,23 +3804,59 @@ fn main() -> Result<(), Box<dyn Error>> {
             "Lines: {}  Invalid: {}  UTF-8 errors: {}",
             parsed.total_lines, parsed.invalid_lines, parsed.utf8_invalid_lines
         );
+        if source_stats.len() > 1 {
+            for st in &source_stats {
+                let _ = writeln!(
+                    stderr,
+                    "  {}: Lines: {}  Invalid: {}  UTF-8 errors: {}",
+                    st.name, st.total_lines, st.invalid_lines, st.utf8_invalid_lines
+                );
+            }
+        }
+        if cli.input_auto {
+            let _ = writeln!(
+                stderr,
+                "Forms: CIDR: {}  Address: {}  Mask: {}  Range: {}  Pattern: {}  Integer: {}",
+                parsed.forms.cidr,
+                parsed.forms.address,
+                parsed.forms.mask,
+                parsed.forms.range,
+                parsed.forms.pattern,
+                parsed.forms.integer
+            );
+        }
+        if cli.scan {
+            let _ = writeln!(
+                stderr,
+                "Scan: {} matches in {} lines (at most {} per line)",
+                parsed.scan.matches, parsed.scan.lines, parsed.scan.max_per_line
+            );
+        }
         if accept_v4 {
             let _ = writeln!(
                 stderr,
-                "IPv4: {} -> {} aggregated ({} addresses)",
+                "IPv4: {} -> {} aggregated ({} addresses{})",
                 v4_before,
                 result_v4.len(),
-                total_addresses_string(&result_v4)
+                total_addresses_string(&result_v4),
+                overcovered_note(overcovered_v4)
             );
+            if let Some(ref promotions) = promoted_v4 {
+                write_promotions("IPv4", promotions, &mut stderr);
+            }
         }
         if accept_v6 {
             let _ = writeln!(
                 stderr,
-                "IPv6: {} -> {} aggregated ({} addresses)",
+                "IPv6: {} -> {} aggregated ({} addresses{})",
                 v6_before,
                 result_v6.len(),
-                total_addresses_string(&result_v6)
+                total_addresses_string(&result_v6),
+                overcovered_note(overcovered_v6)
             );
+            if let Some(ref promotions) = promoted_v6 {
+                write_promotions("IPv6", promotions, &mut stderr);
+            }
         }
     }
 
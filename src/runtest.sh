@@ -85,6 +85,24 @@ for ipv4test in "0.0.0.0-255.255.255.255" "0.0.0.1-255.255.255.254" "29.4.5.66-1
   echo "${ipv4test}" | raggre -4 --input-range|raggre -4 --output-range|grep -qx "${ipv4test}" || fail "ipv4 input-range/output-range roundtrip for \"${ipv4test}\""
 done
 
+# ---------------------------------------------------------------------------
+# cascading merges
+# ---------------------------------------------------------------------------
+
+# every /32 of a /22 collapses into a single prefix
+run
+_RESULT=$(for a in 0 1 2 3; do for b in $(seq 0 255); do echo "10.9.${a}.${b}"; done; done | raggre -4)
+[ "${_RESULT}" = "10.9.0.0/22" ] || fail "cascading /32 -> /22 merge: got '${_RESULT}'"
+
+# merges across mixed prefix lengths, in unsorted order
+run
+_RESULT=$(printf '10.0.3.0/24\n10.0.0.0/25\n10.0.2.0/24\n10.0.1.0/24\n10.0.0.128/25\n10.0.0.5\n' | raggre -4)
+[ "${_RESULT}" = "10.0.0.0/22" ] || fail "mixed-length merge: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '2001:db8::/33\n2001:db8:8000::/34\n2001:db8:c000::/34\n2001:db9::/32\n' | raggre -6)
+[ "${_RESULT}" = "2001:db8::/31" ] || fail "v6 cascading merge: got '${_RESULT}'"
+
 # ---------------------------------------------------------------------------
 # stdin input (no file argument)
 # ---------------------------------------------------------------------------
@@ -93,6 +111,43 @@ run
 _RESULT=$(printf '10.0.0.0/24\n10.0.1.0/24\n' | raggre -4)
 [ "${_RESULT}" = "10.0.0.0/23" ] || fail "stdin input: got '${_RESULT}', expected '10.0.0.0/23'"
 
+# ---------------------------------------------------------------------------
+# multiple input files, "-", directories and globs
+# ---------------------------------------------------------------------------
+
+run
+_TMPA="${_TMPDIR}/multi_a.txt"
+_TMPB="${_TMPDIR}/multi_b.txt"
+printf '10.0.0.0/24\n' > "${_TMPA}"
+printf '10.0.1.0/24\njunk\n' > "${_TMPB}"
+_RESULT=$(raggre -4 "${_TMPA}" "${_TMPB}")
+[ "${_RESULT}" = "10.0.0.0/23" ] || fail "multiple files: got '${_RESULT}', expected '10.0.0.0/23'"
+
+run
+_RESULT=$(printf '10.0.2.0/23\n' | raggre -4 "${_TMPA}" - "${_TMPB}")
+[ "${_RESULT}" = "10.0.0.0/22" ] || fail "files + stdin: got '${_RESULT}', expected '10.0.0.0/22'"
+
+run
+_RESULT=$(raggre -4 --stats "${_TMPA}" "${_TMPB}" 2>&1 >/dev/null)
+echo "${_RESULT}" | grep -q "multi_b.txt: Lines: 2  Invalid: 1" || fail "--stats per-file counters: ${_RESULT}"
+
+run
+raggre -4 --json --stats "${_TMPA}" "${_TMPB}" | grep -q '"file": ".*multi_a.txt"' || fail "--json --stats missing per-file entry"
+
+run
+mkdir -p "${_TMPDIR}/multi_dir/sub"
+printf '10.0.2.0/24\n' > "${_TMPDIR}/multi_dir/x.txt"
+printf '10.0.3.0/24\n' > "${_TMPDIR}/multi_dir/sub/y.txt"
+_RESULT=$(raggre -4 "${_TMPDIR}/multi_dir")
+[ "${_RESULT}" = "10.0.2.0/23" ] || fail "directory input: got '${_RESULT}', expected '10.0.2.0/23'"
+
+run
+_RESULT=$(raggre -4 "${_TMPDIR}/multi_*.txt")
+[ "${_RESULT}" = "10.0.0.0/23" ] || fail "glob input: got '${_RESULT}', expected '10.0.0.0/23'"
+
+run
+raggre -4 "${_TMPDIR}/nomatch_*.txt" 2>/dev/null && fail "glob matching nothing should fail"
+
 # ---------------------------------------------------------------------------
 # mixed v4+v6 (no -4 or -6 flag)
 # ---------------------------------------------------------------------------
@@ -101,6 +156,532 @@ run
 _RESULT=$(printf '10.0.0.0/24\n2001:db8::/32\n' | raggre | wc -l | tr -d ' ')
 [ "${_RESULT}" = "2" ] || fail "mixed v4+v6: expected 2 lines, got ${_RESULT}"
 
+# ---------------------------------------------------------------------------
+# --input-auto
+# ---------------------------------------------------------------------------
+
+_TMPA="${_TMPDIR}/auto.txt"
+printf '10.0.0.0/24\n10.1.0.0-10.1.3.255\n192.168.1.1\n2001:db8::/48\n2001:db8:1::-2001:db8:1::ff\n::1\n' > "${_TMPA}"
+
+run
+_RESULT=$(raggre --input-auto "${_TMPA}" | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.0/24 10.1.0.0/22 192.168.1.1/32 ::1/128 2001:db8::/48 2001:db8:1::/120 " ] || fail "--input-auto mixed forms: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --input-auto --stats "${_TMPA}" 2>&1 >/dev/null)
+echo "${_RESULT}" | grep -q '^Forms: CIDR: 2  Address: 2  Mask: 0  Range: 2 ' || fail "--input-auto --stats forms: ${_RESULT}"
+
+# a line that looks like a range reports the range error
+run
+_TMPB="${_TMPDIR}/auto_rejects.txt"
+printf '10.0.0.9-10.0.0.1\n' | raggre --input-auto --rejects "${_TMPB}" > /dev/null
+grep -q 'range start is greater than range end' "${_TMPB}" || fail "--input-auto range error: $(cat "${_TMPB}")"
+
+run
+raggre --input-auto --input-range < /dev/null 2>/dev/null && fail "--input-auto + --input-range should fail"
+
+# ---------------------------------------------------------------------------
+# netmask / wildcard input
+# ---------------------------------------------------------------------------
+
+# --output-netmask and --output-wildcard read back to the same prefixes
+run
+_RESULT=$(raggre test4.txt | raggre --output-netmask | raggre | cmp -s - test4-ok.txt) || fail "--output-netmask does not round-trip"
+
+run
+_RESULT=$(raggre test4.txt | raggre --output-wildcard | raggre | cmp -s - test4-ok.txt) || fail "--output-wildcard does not round-trip"
+
+# /0 and /32 survive the wildcard round trip too
+run
+_RESULT=$(printf '0.0.0.0/0\n' | raggre --output-wildcard)
+[ "${_RESULT}" = "0.0.0.0/0" ] || fail "--output-wildcard /0: got '${_RESULT}'"
+_RESULT=$(printf '0.0.0.0/0\n' | raggre --output-wildcard | raggre)
+[ "${_RESULT}" = "0.0.0.0/0" ] || fail "--output-wildcard /0 round trip: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '10.1.2.3/32\n' | raggre --output-wildcard)
+[ "${_RESULT}" = "10.1.2.3 0.0.0.0" ] || fail "--output-wildcard /32: got '${_RESULT}'"
+_RESULT=$(printf '10.1.2.3/32\n' | raggre --output-wildcard | raggre)
+[ "${_RESULT}" = "10.1.2.3/32" ] || fail "--output-wildcard /32 round trip: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '2001:db8::/ffff:ffff::\n' | raggre -6)
+[ "${_RESULT}" = "2001:db8::/32" ] || fail "IPv6 netmask input: got '${_RESULT}'"
+
+# after whitespace 0.0.0.0 is a wildcard (host), as in Cisco ACLs
+run
+_RESULT=$(printf '10.0.0.0 255.255.255.0\n10.9.9.9 0.0.0.0\n' | raggre -4 | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.0/24 10.9.9.9/32 " ] || fail "netmask/wildcard after whitespace: got '${_RESULT}'"
+
+# 255.255.255.255 after whitespace is a host netmask (Windows route print), never /0
+run
+_RESULT=$(printf '1.2.3.4 255.255.255.255\n' | raggre -4)
+[ "${_RESULT}" = "1.2.3.4/32" ] || fail "host netmask after whitespace: got '${_RESULT}'"
+
+run
+_TMPB="${_TMPDIR}/mask_rejects.txt"
+printf '10.1.0.0 0.255.0.255\n' | raggre -4 --rejects "${_TMPB}" > /dev/null
+grep -q 'non-contiguous mask "0.255.0.255"' "${_TMPB}" || fail "non-contiguous mask should be rejected: $(cat "${_TMPB}")"
+
+run
+_RESULT=$(printf '10.1.0.0 0.1.0.255\n192.168.0.0/255.255.254.255\n' | raggre -4 --expand-wildcards | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.0/24 10.1.0.0/24 192.168.0.0/32 192.168.1.0/32 " ] || fail "--expand-wildcards: got '${_RESULT}'"
+
+run
+printf '10.0.0.0 0.255.255.254\n' | raggre -4 --expand-wildcards --strict > /dev/null 2>&1
+[ $? -eq 3 ] || fail "--expand-wildcards should respect --expand-limit"
+
+run
+_RESULT=$(printf '10.0.0.0 0.255.255.254\n' | raggre -4 --expand-wildcards --expand-limit 8388608 | wc -l | tr -d ' ')
+[ "${_RESULT}" = "8388608" ] || fail "--expand-limit 8388608: got ${_RESULT} lines"
+
+# ---------------------------------------------------------------------------
+# --input-nmap
+# ---------------------------------------------------------------------------
+
+run
+_RESULT=$(printf '10.0.0-3.*\n' | raggre --input-nmap)
+[ "${_RESULT}" = "10.0.0.0/22" ] || fail "--input-nmap octet range + glob: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '192.168.1.10-50\n' | raggre --input-nmap | raggre --output-range)
+[ "${_RESULT}" = "192.168.1.10-192.168.1.50" ] || fail "--input-nmap short range: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '172.16.*.1\n' | raggre --input-nmap | wc -l | tr -d ' ')
+[ "${_RESULT}" = "256" ] || fail "--input-nmap inner glob: got ${_RESULT} lines"
+
+run
+_RESULT=$(printf '10.1/16\n10/8\n172.16/12\n' | raggre --input-nmap | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.0/8 172.16.0.0/12 " ] || fail "--input-nmap short prefixes: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '10.0.0.1,3,5-7\n' | raggre --input-nmap | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.1/32 10.0.0.3/32 10.0.0.5/32 10.0.0.6/31 " ] || fail "--input-nmap octet list: got '${_RESULT}'"
+
+# plain prefixes and (with --input-auto) full ranges still work
+run
+_RESULT=$(printf '10.0.0.0/24\n10.0.1.0-10.0.1.255\n10.0.2-3.*\n' | raggre --input-nmap --input-auto)
+[ "${_RESULT}" = "10.0.0.0/22" ] || fail "--input-nmap with --input-auto: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '10.0.0-3.*\n' | raggre --input-nmap --input-auto --stats 2>&1 >/dev/null)
+echo "${_RESULT}" | grep -q 'Pattern: 1 ' || fail "--input-nmap --stats pattern count: ${_RESULT}"
+
+# the expansion guard rejects the line instead of allocating
+run
+_TMPB="${_TMPDIR}/nmap_rejects.txt"
+printf '10.*.*.1\n' | raggre --input-nmap --expand-limit 1000 --rejects "${_TMPB}" > /dev/null
+grep -q 'expands to 65536 prefixes, more than the limit of 1000' "${_TMPB}" || fail "--input-nmap expansion guard: $(cat "${_TMPB}")"
+
+run
+_TMPB="${_TMPDIR}/nmap_rejects.txt"
+printf '10.9-1.0.0\n' | raggre --input-nmap --rejects "${_TMPB}" > /dev/null
+grep -q 'range start is greater than range end' "${_TMPB}" || fail "--input-nmap octet range order: $(cat "${_TMPB}")"
+
+# ---------------------------------------------------------------------------
+# integer / hex input and output
+# ---------------------------------------------------------------------------
+
+run
+_RESULT=$(printf '167772160\n0x0a000100/24\n' | raggre --input-int)
+[ "${_RESULT}" = "10.0.0.0/32
+10.0.1.0/24" ] || fail "--input-int decimal + hex prefix: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '10.0.0.0/24\n10.0.1.0/24\n' | raggre --output-int-range)
+[ "${_RESULT}" = "167772160-167772671" ] || fail "--output-int-range: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '10.0.0.0/8\n2001:db8::/32\n' | raggre --output-hex-cidr | tr '\n' ' ')
+[ "${_RESULT}" = "0x0a000000/8 0x20010db8000000000000000000000000/32 " ] || fail "--output-hex-cidr: got '${_RESULT}'"
+
+# both output forms read back to the same aggregate
+run
+_TMPB="${_TMPDIR}/int_roundtrip.txt"
+ruran4 200 --prefixes 8-32 | raggre > "${_TMPB}"
+_RESULT=$(raggre --output-int-range "${_TMPB}" | raggre --input-int | cmp - "${_TMPB}" && \
+          raggre --output-hex-cidr "${_TMPB}" | raggre --input-int | cmp - "${_TMPB}" && echo same)
+[ "${_RESULT}" = "same" ] || fail "--input-int round trip differs"
+
+run
+_RESULT=$(printf '5\n' | raggre --input-int -6)
+[ "${_RESULT}" = "::5/128" ] || fail "--input-int -6 small value: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '10.0.0.0/8\n0xc0a80000/16\n' | raggre --input-auto --input-int --stats 2>&1 >/dev/null)
+echo "${_RESULT}" | grep -q 'Integer: 1$' || fail "--input-int --stats integer count: ${_RESULT}"
+
+run
+_TMPB="${_TMPDIR}/int_rejects.txt"
+printf '0x0a000001/24\n' | raggre --input-int --ignore-invalid --rejects "${_TMPB}" > /dev/null
+[ -s "${_TMPB}" ] || fail "--input-int --ignore-invalid host bits not rejected"
+
+# ---------------------------------------------------------------------------
+# --comment and --dialect
+# ---------------------------------------------------------------------------
+
+run
+_RESULT=$(printf '# header\n10.0.0.0/24 # a\n10.0.1.0/24 // b\n' | raggre --comment '#,//')
+[ "${_RESULT}" = "10.0.0.0/23" ] || fail "--comment trailing comments: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '# header\n10.0.0.0/24 # a\n' | raggre --comment '#' --stats 2>&1 >/dev/null)
+echo "${_RESULT}" | grep -q '^Lines: 1  Invalid: 0 ' || fail "--comment-only lines skipped: ${_RESULT}"
+
+# without markers a commented line is still invalid
+run
+_RESULT=$(printf '10.0.0.0/24 ; x\n' | raggre --stats 2>&1 >/dev/null)
+echo "${_RESULT}" | grep -q 'Invalid: 1 ' || fail "no --comment keeps comments invalid: ${_RESULT}"
+
+run
+_RESULT=$(printf '; Spamhaus DROP List\n1.10.16.0/20 ; SBL256894\n1.19.0.0/16 ; SBL434604\n' | raggre --dialect spamhaus | tr '\n' ' ')
+[ "${_RESULT}" = "1.10.16.0/20 1.19.0.0/16 " ] || fail "--dialect spamhaus: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '#\n# netset\n#\n10.0.0.0/25\n10.0.0.128/25\n' | raggre --dialect firehol)
+[ "${_RESULT}" = "10.0.0.0/24" ] || fail "--dialect firehol: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '# hosts\n127.0.0.1 localhost\n::1 localhost ip6-localhost\n0.0.0.0 ads.example # blocked\n' | raggre --dialect hosts | tr '\n' ' ')
+[ "${_RESULT}" = "0.0.0.0/32 127.0.0.1/32 ::1/128 " ] || fail "--dialect hosts: got '${_RESULT}'"
+
+run
+_RESULT=$(printf 'a,10.0.0.0/24 # y\n' | raggre -d , -f 2 --comment '#')
+[ "${_RESULT}" = "10.0.0.0/24" ] || fail "--comment with --fields: got '${_RESULT}'"
+
+run
+raggre --dialect hosts -d , -f 1 < /dev/null > /dev/null 2>&1 && fail "--dialect with --fields should fail"
+
+# ---------------------------------------------------------------------------
+# --keep-comments
+# ---------------------------------------------------------------------------
+
+run
+_TMPA="${_TMPDIR}/commented.txt"
+printf '10.0.0.0/24 # customer-42\n10.0.1.0/24 # customer-7\n10.0.1.0/25 # customer-42\n192.168.0.0/24\n' > "${_TMPA}"
+_RESULT=$(raggre --comment '#' --keep-comments "${_TMPA}" | tr '\n' '|')
+[ "${_RESULT}" = "10.0.0.0/23 # customer-42, customer-7|192.168.0.0/24|" ] || fail "--keep-comments: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --comment '#' --keep-comments --max-comments 1 "${_TMPA}" | head -1)
+[ "${_RESULT}" = "10.0.0.0/23 # customer-42, +1 more" ] || fail "--max-comments: got '${_RESULT}'"
+
+# the output reads back with the same marker
+run
+_RESULT=$(raggre --comment '#' --keep-comments "${_TMPA}" | raggre --comment '#' | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.0/23 192.168.0.0/24 " ] || fail "--keep-comments round trip: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --comment '#' --keep-comments --json "${_TMPA}" | tr -d ' \n')
+echo "${_RESULT}" | grep -q '"block":"10.0.0.0/23","comments":\["customer-42","customer-7"\]' \
+    || fail "--keep-comments --json: ${_RESULT}"
+
+run
+_RESULT=$(printf '1.10.16.0/20 ; SBL256894\n1.10.32.0/20 ; SBL1\n' | raggre --dialect spamhaus --keep-comments --max-entries 1)
+[ "${_RESULT}" = "1.10.0.0/18 ; SBL256894, SBL1" ] || fail "--keep-comments with --max-entries: got '${_RESULT}'"
+
+run
+raggre --keep-comments < /dev/null > /dev/null 2>&1 && fail "--keep-comments without markers should fail"
+
+# ---------------------------------------------------------------------------
+# --explain
+# ---------------------------------------------------------------------------
+
+run
+_TMPA="${_TMPDIR}/explain_a.txt"
+_TMPB="${_TMPDIR}/explain_b.txt"
+printf '10.0.0.0/24\n10.0.1.0/24\n10.0.2.0/23\n192.168.0.0/24\n10.0.0.0/25\n' > "${_TMPA}"
+printf '10.0.3.0/24\n' > "${_TMPB}"
+_RESULT=$(raggre --explain "${_TMPA}" "${_TMPB}" | tr '\n' '|')
+[ "${_RESULT}" = "10.0.0.0/22: 5 inputs|  ${_TMPA}: 1-3, 5|  ${_TMPB}: 1|192.168.0.0/24: 1 input|  ${_TMPA}: 4|" ] \
+    || fail "--explain report: got '${_RESULT}'"
+
+# excluded inputs no longer explain anything
+run
+_RESULT=$(printf '192.168.0.0/16\n' | raggre --explain --exclude - "${_TMPA}" | tr '\n' '|')
+[ "${_RESULT}" = "10.0.0.0/22: 4 inputs|  ${_TMPA}: 1-3, 5|" ] || fail "--explain with --exclude: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --explain --json "${_TMPA}" | tr -d ' \n')
+echo "${_RESULT}" | grep -q '"block":"10.0.0.0/22","inputs":4,"sources":\[{"file":"[^"]*","lines":\[1,2,3,5\]}\]' \
+    || fail "--explain --json: ${_RESULT}"
+
+# a range expanding to several prefixes is one input line
+run
+_RESULT=$(printf '10.0.0.0-10.0.0.2\n' | raggre --explain --input-range | head -2 | tr '\n' '|')
+[ "${_RESULT}" = "10.0.0.0/31: 1 input|  -: 1|" ] || fail "--explain range input: got '${_RESULT}'"
+
+# ---------------------------------------------------------------------------
+# compressed input
+# ---------------------------------------------------------------------------
+
+_TMPA="${_TMPDIR}/compressed.txt"
+printf '10.0.0.0/24\n10.0.1.0/24\n' > "${_TMPA}"
+for _TOOL in gzip:gz xz:xz zstd:zst bzip2:bz2; do
+    command -v "${_TOOL%%:*}" > /dev/null || continue
+    run
+    "${_TOOL%%:*}" -c -q < "${_TMPA}" > "${_TMPA}.${_TOOL#*:}"
+    _RESULT=$(raggre "${_TMPA}.${_TOOL#*:}")
+    [ "${_RESULT}" = "10.0.0.0/23" ] || fail "${_TOOL%%:*} input: got '${_RESULT}'"
+done
+
+if command -v gzip > /dev/null; then
+    # concatenated members, stdin and --exclude/--diff files are decoded too
+    run
+    cat "${_TMPA}.gz" "${_TMPA}.gz" > "${_TMPDIR}/twice.gz"
+    _RESULT=$(raggre --stats "${_TMPDIR}/twice.gz" 2>&1 >/dev/null | head -1)
+    [ "${_RESULT}" = "Lines: 4  Invalid: 0  UTF-8 errors: 0" ] || fail "concatenated gzip: got '${_RESULT}'"
+
+    run
+    _RESULT=$(raggre < "${_TMPA}.gz")
+    [ "${_RESULT}" = "10.0.0.0/23" ] || fail "gzip on stdin: got '${_RESULT}'"
+
+    run
+    _RESULT=$(printf '10.0.0.0/16\n' | raggre --exclude "${_TMPA}.gz" - | head -1)
+    [ "${_RESULT}" = "10.0.2.0/23" ] || fail "gzip --exclude file: got '${_RESULT}'"
+
+    run
+    printf '10.0.0.0/24\n' | gzip -c > "${_TMPDIR}/old.gz"
+    _RESULT=$(raggre --diff "${_TMPDIR}/old.gz" "${_TMPA}.gz")
+    [ "${_RESULT}" = "+ 10.0.1.0/24" ] || fail "gzip --diff files: got '${_RESULT}'"
+
+    run
+    head -c 12 "${_TMPA}.gz" > "${_TMPDIR}/cut.gz"
+    raggre "${_TMPDIR}/cut.gz" > /dev/null 2>&1 && fail "truncated gzip should fail"
+fi
+
+# ---------------------------------------------------------------------------
+# --regex
+# ---------------------------------------------------------------------------
+
+run
+_TMPA="${_TMPDIR}/regex_log.txt"
+printf 'sshd: fail client=10.0.0.1 port 22\nsshd: fail client=10.0.0.0 port 22\nno match here\n' > "${_TMPA}"
+_RESULT=$(raggre --regex 'client=([0-9.]+)' "${_TMPA}")
+[ "${_RESULT}" = "10.0.0.0/31" ] || fail "--regex numbered group: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --regex 'client=([0-9.]+)' --stats "${_TMPA}" 2>&1 >/dev/null | grep "^Lines:")
+[ "${_RESULT}" = "Lines: 3  Invalid: 1  UTF-8 errors: 0" ] || fail "--regex non-matching line: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '[a] [b] [192.168.1.1]\n' | raggre --regex '\[([^]]*)\] \[(?P<second>[^]]*)\] \[(?P<third>[^]]*)\]' --regex-groups third)
+[ "${_RESULT}" = "192.168.1.1/32" ] || fail "--regex named group: got '${_RESULT}'"
+
+# without groups the whole match is used
+run
+_RESULT=$(printf 'from 2001:db8::1 ok\n' | raggre --regex '[0-9a-f:]{3,}')
+[ "${_RESULT}" = "2001:db8::1/128" ] || fail "--regex whole match: got '${_RESULT}'"
+
+run
+_RESULT=$(printf 'client=10.0.0.9 \377\n' | raggre --regex 'client=(\S+)' --stats 2>&1 >/dev/null | grep "^Lines:")
+[ "${_RESULT}" = "Lines: 0  Invalid: 0  UTF-8 errors: 1" ] || fail "--regex UTF-8 accounting: got '${_RESULT}'"
+
+run
+raggre --regex '(a)' --regex-groups 2 < /dev/null > /dev/null 2>&1 && fail "--regex-groups out of range should fail"
+
+# ---------------------------------------------------------------------------
+# --scan
+# ---------------------------------------------------------------------------
+
+_TMPA="${_TMPDIR}/scan.txt"
+cat > "${_TMPA}" <<'EOF'
+Hi team, we saw attacks from 203.0.113.7:8080 and [2001:db8::1]:443 today.
+Please block 198.51.100.0/24, also 10.0.0.1-10.0.0.3 and host-192.0.2.5.
+Router: Gi0/1 up, uptime 12:34:56, version 15.2.4, MAC aa:bb:cc:dd:ee:ff
+Note:: nothing on 2024-01-02; deadbeef cafe ::ffff:192.0.2.9 fe80::1%eth0
+EOF
+
+run
+_RESULT=$(raggre --scan "${_TMPA}" | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.1/32 10.0.0.2/31 192.0.2.5/32 198.51.100.0/24 203.0.113.7/32 ::ffff:192.0.2.9/128 2001:db8::1/128 fe80::1/128 " ] \
+    || fail "--scan: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --scan --stats "${_TMPA}" 2>&1 >/dev/null | head -2 | tr '\n' '|')
+[ "${_RESULT}" = "Lines: 4  Invalid: 0  UTF-8 errors: 0|Scan: 7 matches in 3 lines (at most 3 per line)|" ] \
+    || fail "--scan --stats: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --scan --stats --json "${_TMPA}" | tr -d ' \n')
+echo "${_RESULT}" | grep -q '"scan":{"matches":7,"lines":3,"max_per_line":3}' || fail "--scan --json stats: ${_RESULT}"
+
+run
+_RESULT=$(printf 'from 10.9.9.9 \377\n' | raggre --scan)
+[ "${_RESULT}" = "10.9.9.9/32" ] || fail "--scan invalid UTF-8 line: got '${_RESULT}'"
+
+# IPv6 written in hex letters only; "::" and words like "cafe" are not addresses
+run
+_RESULT=$(printf 'see dead::beef here\nfe80::abcd, :: and cafe\n' | raggre --scan | tr '\n' ' ')
+[ "${_RESULT}" = "dead::beef/128 fe80::abcd/128 " ] || fail "--scan hex-letter IPv6: got '${_RESULT}'"
+
+run
+raggre --scan --input-range < /dev/null > /dev/null 2>&1 && fail "--scan with --input-range should fail"
+
+# ---------------------------------------------------------------------------
+# --grep
+# ---------------------------------------------------------------------------
+
+_TMPA="${_TMPDIR}/grep_set.txt"
+_TMPB="${_TMPDIR}/grep_log.txt"
+printf '10.0.0.0/24\n10.0.1.0/24\n2001:db8::/32\n' > "${_TMPA}"
+printf 'GET / client=10.0.0.5 ok\nGET / client=10.0.2.5 ok\nv6 [2001:db8::1]:443\nnothing\nboth 10.0.2.1 10.0.1.9\n' > "${_TMPB}"
+
+run
+_RESULT=$(raggre --grep "${_TMPA}" "${_TMPB}" | tr '\n' '|')
+[ "${_RESULT}" = "GET / client=10.0.0.5 ok|v6 [2001:db8::1]:443|both 10.0.2.1 10.0.1.9|" ] || fail "--grep: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --grep "${_TMPA}" -v "${_TMPB}" | tr '\n' '|')
+[ "${_RESULT}" = "GET / client=10.0.2.5 ok|nothing|" ] || fail "--grep -v: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --grep "${_TMPA}" --regex 'client=(\S+)' "${_TMPB}")
+[ "${_RESULT}" = "GET / client=10.0.0.5 ok" ] || fail "--grep --regex: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --grep "${_TMPA}" -d ' ' -f 3 "${_TMPB}")
+[ "${_RESULT}" = "both 10.0.2.1 10.0.1.9" ] || fail "--grep --fields: got '${_RESULT}'"
+
+# the CSV header is kept so the output stays a valid CSV
+run
+_RESULT=$(printf 'ip,name\n10.0.0.1,a\n10.9.0.1,b\n' | raggre --grep "${_TMPA}" --csv-field-name ip | tr '\n' '|')
+[ "${_RESULT}" = "ip,name|10.0.0.1,a|" ] || fail "--grep --csv-field-name: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --grep "${_TMPA}" --stats "${_TMPB}" 2>&1 >/dev/null | tr '\n' '|')
+[ "${_RESULT}" = "Set: 1 IPv4 and 1 IPv6 prefixes|Lines: 5  Selected: 3|" ] || fail "--grep --stats: got '${_RESULT}'"
+
+# ---------------------------------------------------------------------------
+# --lookup
+# ---------------------------------------------------------------------------
+
+_TMPA="${_TMPDIR}/lookup_set.txt"
+printf '10.0.0.0/8 # corp\n10.1.0.0/16 # lab\n2001:db8::/32 # doc\n' > "${_TMPA}"
+
+run
+_RESULT=$(raggre --comment '#' --lookup "${_TMPA}" 10.1.2.3 2001:db8::1 | tr '\n' '|')
+[ "${_RESULT}" = "10.1.2.3: 10.1.0.0/16 # lab|2001:db8::1: 2001:db8::/32 # doc|" ] || fail "--lookup: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --comment '#' --lookup "${_TMPA}" --lookup-all 10.1.2.3 | tr '\n' '|')
+[ "${_RESULT}" = "10.1.2.3: 10.0.0.0/8 # corp|10.1.2.3: 10.1.0.0/16 # lab|" ] || fail "--lookup-all: got '${_RESULT}'"
+
+run
+_RESULT=$(echo 192.0.2.1 | raggre --comment '#' --lookup "${_TMPA}")
+[ $? -eq 1 ] && [ "${_RESULT}" = "192.0.2.1: no match" ] || fail "--lookup no match: got '${_RESULT}'"
+
+run
+raggre --lookup-all 10.0.0.1 2>/dev/null && fail "--lookup-all without --lookup should fail"
+
+# exit status as grep: 0 on a match, 1 on no match, 2 on trouble
+run
+raggre --comment '#' --lookup "${_TMPA}" 10.9.9.9 > /dev/null
+_RESULT=$?
+[ "${_RESULT}" = "0" ] || fail "--lookup match status: got '${_RESULT}'"
+
+run
+raggre --comment '#' --lookup "${_TMPA}" 192.0.2.1 > /dev/null
+_RESULT=$?
+[ "${_RESULT}" = "1" ] || fail "--lookup miss status: got '${_RESULT}'"
+
+run
+raggre --comment '#' --lookup "${_TMPA}" 10.9.9.9 bogus > /dev/null 2>&1
+_RESULT=$?
+[ "${_RESULT}" = "2" ] || fail "--lookup invalid query status: got '${_RESULT}'"
+
+run
+raggre --lookup "${_TMPDIR}/missing.txt" 10.9.9.9 > /dev/null 2>&1
+_RESULT=$?
+[ "${_RESULT}" = "2" ] || fail "--lookup missing SETFILE status: got '${_RESULT}'"
+
+# a bad SETFILE line is skipped, not an error, unless --strict is given
+run
+_TMPB="${_TMPDIR}/lookup_bad_set.txt"
+printf '10.0.0.0/8 # corp\nbogus\n' > "${_TMPB}"
+raggre --comment '#' --lookup "${_TMPB}" 10.9.9.9 > /dev/null 2>&1
+_RESULT=$?
+[ "${_RESULT}" = "0" ] || fail "--lookup bad SETFILE line status: got '${_RESULT}'"
+raggre --comment '#' --lookup "${_TMPB}" --strict 10.9.9.9 > /dev/null 2>&1
+_RESULT=$?
+[ "${_RESULT}" = "3" ] || fail "--lookup --strict bad SETFILE line status: got '${_RESULT}'"
+
+# ---------------------------------------------------------------------------
+# --annotate
+# ---------------------------------------------------------------------------
+
+_TMPA="${_TMPDIR}/annotate_table.csv"
+_TMPB="${_TMPDIR}/annotate_log.txt"
+printf 'prefix,asn,owner\n10.0.0.0/8,AS1,"Corp, Inc"\n10.1.0.0/16,AS2,Lab\n2001:db8::/32,AS3,Doc\n' > "${_TMPA}"
+printf 'GET client=10.1.2.3 ok\nfrom [2001:db8::5]:443\nnothing\n' > "${_TMPB}"
+
+run
+_RESULT=$(raggre --annotate "${_TMPA}" --table-field-name prefix "${_TMPB}" | tr '\n' '|')
+[ "${_RESULT}" = "GET client=10.1.2.3 ok AS2 Lab|from [2001:db8::5]:443 AS3 Doc|nothing - -|" ] || fail "--annotate: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --annotate "${_TMPA}" --table-field-name prefix --table-label-names owner --annotate-placeholder NA "${_TMPB}" | tr '\n' '|')
+[ "${_RESULT}" = "GET client=10.1.2.3 ok Lab|from [2001:db8::5]:443 Doc|nothing NA|" ] || fail "--annotate --table-label-names: got '${_RESULT}'"
+
+# CSV input gets CSV-quoted labels and an extended header line
+run
+_RESULT=$(printf 'ip,n\n10.9.0.1,a\n8.8.8.8,b\n' | raggre --annotate "${_TMPA}" --table-field-name prefix --csv-field-name ip | tr '\n' '|')
+[ "${_RESULT}" = 'ip,n,asn,owner|10.9.0.1,a,AS1,"Corp, Inc"|8.8.8.8,b,-,-|' ] || fail "--annotate --csv-field-name: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '10.1.0.9:a\n' | raggre --annotate "${_TMPA}" --table-field-name prefix -d : -f 1 --table-label-numbers 2)
+[ "${_RESULT}" = "10.1.0.9:a:AS2" ] || fail "--annotate --fields: got '${_RESULT}'"
+
+# lines that are not UTF-8 are passed through byte for byte
+run
+printf 'x 10.1.0.1 \377\r\n' | raggre --annotate "${_TMPA}" --table-field-name prefix --table-label-names asn > "${_TMPB}"
+printf 'x 10.1.0.1 \377 AS2\n' | cmp -s - "${_TMPB}" || fail "--annotate non-UTF-8 line: got '$(od -c "${_TMPB}")'"
+
+run
+raggre --table-field-number 2 10.0.0.1 2>/dev/null && fail "--table-field-number without --annotate should fail"
+
+run
+_TMPB="${_TMPDIR}/annotate_rejects.txt"
+printf 'bad,"x, y"\n' > "${_TMPA}"
+echo 10.0.0.1 | raggre --annotate "${_TMPA}" --rejects "${_TMPB}" > /dev/null
+grep -qx "${_TMPA}:1: invalid address \"bad\": bad,\"x, y\"" "${_TMPB}" || fail "--annotate table rejects: $(cat "${_TMPB}")"
+
+# ---------------------------------------------------------------------------
+# --group-by-field
+# ---------------------------------------------------------------------------
+
+_TMPA="${_TMPDIR}/group.csv"
+printf 'country,prefix\nDE,10.0.0.0/25\nFR,192.0.2.0/24\nDE,10.0.0.128/25\nFR,2001:db8::/32\n' > "${_TMPA}"
+
+run
+_RESULT=$(raggre --group-by-field country --csv-field-name prefix "${_TMPA}" | tr '\n' '|')
+[ "${_RESULT}" = "DE 10.0.0.0/24|FR 192.0.2.0/24|FR 2001:db8::/32|" ] || fail "--group-by-field CSV: got '${_RESULT}'"
+
+run
+_RESULT=$(printf 'AS1 10.0.0.0/24\nAS2 1.2.3.4\nAS1 10.0.1.0/24\n' | raggre -d ' ' -f 2 --group-by-field 1 | tr '\n' '|')
+[ "${_RESULT}" = "AS1 10.0.0.0/23|AS2 1.2.3.4/32|" ] || fail "--group-by-field --fields: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre --group-by-field 1 --csv-field-number 2 --json "${_TMPA}" | tr -d ' \n')
+[ "${_RESULT}" = '{"groups":{"DE":{"results4":["10.0.0.0/24"],"results6":[]},"FR":{"results4":["192.0.2.0/24"],"results6":["2001:db8::/32"]}}}' ] || fail "--group-by-field --json: got '${_RESULT}'"
+
+run
+_TMPB="${_TMPDIR}/groups"
+raggre --group-by-field country --csv-field-name prefix --group-output-dir "${_TMPB}" "${_TMPA}"
+_RESULT=$(ls "${_TMPB}" | tr '\n' ' ')$(cat "${_TMPB}/FR" | tr '\n' '|')
+[ "${_RESULT}" = "DE FR 192.0.2.0/24|2001:db8::/32|" ] || fail "--group-output-dir: got '${_RESULT}'"
+
+# keys mapping to the same file name fail before anything is written
+run
+_TMPB="${_TMPDIR}/groups_clash"
+printf 'a/b 10.0.0.1\na_b 10.0.0.2\n' | raggre -d ' ' -f 2 --group-by-field 1 --group-output-dir "${_TMPB}" 2>/dev/null \
+    && fail "--group-output-dir with clashing keys should fail"
+[ -e "${_TMPB}" ] && fail "--group-output-dir with clashing keys should write nothing"
+
+run
+raggre --group-by-field 1 10.0.0.1 2>/dev/null && fail "--group-by-field without --fields or CSV should fail"
+
 # ---------------------------------------------------------------------------
 # --max-length
 # ---------------------------------------------------------------------------
@@ -120,6 +701,76 @@ run
 _RESULT=$(printf '10.0.0.0/32\n10.0.0.1/32\n' | raggre -4 -m 31)
 [ "${_RESULT}" = "10.0.0.0/31" ] || fail "--max-length /31 aggregate: got '${_RESULT}'"
 
+# ---------------------------------------------------------------------------
+# --min-length
+# ---------------------------------------------------------------------------
+
+# a /22 becomes four /24s, longer prefixes are left alone
+run
+_RESULT=$(printf '10.0.0.0/22\n10.1.0.0/25\n' | raggre -4 --min-length 24 | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.0/24 10.0.1.0/24 10.0.2.0/24 10.0.3.0/24 10.1.0.0/25 " ] || fail "--min-length 24: got '${_RESULT}'"
+
+# lengths above 32 only split IPv6
+run
+_RESULT=$(printf '10.0.0.0/16\n2001:db8::/63\n' | raggre --min-length 64 | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.0/16 2001:db8::/64 2001:db8:0:1::/64 " ] || fail "--min-length 64 mixed: got '${_RESULT}'"
+
+# splitting happens after aggregation, so the output re-aggregates to the input
+run
+_RESULT=$(printf '10.0.0.0/20\n' | raggre -4 --min-length 28 | raggre -4)
+[ "${_RESULT}" = "10.0.0.0/20" ] || fail "--min-length round trip: got '${_RESULT}'"
+
+run
+printf '2001:db8::/32\n' | raggre -6 --min-length 64 > /dev/null 2>&1 && fail "--min-length over --split-limit should fail"
+
+run
+_RESULT=$(printf '10.0.0.0/8\n' | raggre -4 --min-length 24 --split-limit 65536 | wc -l | tr -d ' ')
+[ "${_RESULT}" = "65536" ] || fail "--min-length at --split-limit: got ${_RESULT} lines"
+
+run
+raggre -4 --min-length 24 -m 16 < /dev/null 2>/dev/null && fail "--min-length greater than --max-length should fail"
+
+# ---------------------------------------------------------------------------
+# --floor4 / --floor6 / --no-merge
+# ---------------------------------------------------------------------------
+
+_TMPA="${_TMPDIR}/floor.txt"
+printf '10.0.0.0/17\n10.0.128.0/17\n10.2.0.0/16\n10.3.0.0/16\n10.3.1.0/24\n2001:db8::/33\n2001:db8:8000::/33\n' > "${_TMPA}"
+
+# the /17s merge up to the floor, the /16s may not merge into a /15
+run
+_RESULT=$(raggre --floor4 16 --floor6 33 "${_TMPA}" | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.0/16 10.2.0.0/16 10.3.0.0/16 2001:db8::/33 2001:db8:8000::/33 " ] || fail "--floor4 16 --floor6 33: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre -4 --floor4 15 "${_TMPA}" | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.0/16 10.2.0.0/15 " ] || fail "--floor4 15: got '${_RESULT}'"
+
+# input blocks already shorter than the floor are kept
+run
+_RESULT=$(printf '10.0.0.0/8\n10.1.0.0/16\n' | raggre -4 --floor4 16)
+[ "${_RESULT}" = "10.0.0.0/8" ] || fail "--floor4 keeps short input: got '${_RESULT}'"
+
+# --no-merge drops the contained /24 but keeps the siblings apart
+run
+_RESULT=$(raggre -4 --no-merge "${_TMPA}" | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.0/17 10.0.128.0/17 10.2.0.0/16 10.3.0.0/16 " ] || fail "--no-merge: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '10.0.0.0/18\n' > "${_TMPDIR}/floor_excl.txt"; raggre -4 --no-merge --exclude "${_TMPDIR}/floor_excl.txt" "${_TMPA}" | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.64.0/18 10.0.128.0/17 10.2.0.0/16 10.3.0.0/16 " ] || fail "--no-merge --exclude: got '${_RESULT}'"
+
+# set operations on non-aggregated input do not re-aggregate the result
+run
+_RESULT=$(printf '10.0.0.0/8\n' > "${_TMPDIR}/floor_isect.txt"; raggre -4 --no-merge --intersect "${_TMPDIR}/floor_isect.txt" "${_TMPA}" | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.0/17 10.0.128.0/17 10.2.0.0/16 10.3.0.0/16 " ] || fail "--no-merge --intersect: got '${_RESULT}'"
+
+run
+raggre --no-merge --floor4 16 < /dev/null 2>/dev/null && fail "--no-merge + --floor4 should fail"
+
+run
+raggre --floor4 33 < /dev/null 2>/dev/null && fail "--floor4 33 should fail"
+
 # ---------------------------------------------------------------------------
 # --exclude
 # ---------------------------------------------------------------------------
@@ -137,6 +788,43 @@ run
 # the excluded /16 must not appear in the result
 raggre -4 --exclude "${_TMPB}" "${_TMPA}" | grep -q "10.1.0.0/16" && fail "--exclude did not remove 10.1.0.0/16"
 
+# exclude everything that is in the result
+run
+_RESULT=$(raggre -4 --exclude "${_TMPA}" "${_TMPB}")
+[ -z "${_RESULT}" ] || fail "--exclude superset should give empty output, got '${_RESULT}'"
+
+# several excludes carved from one block, and one exclude spanning several blocks
+run
+_TMPA="${_TMPDIR}/excl_multi_a.txt"
+_TMPB="${_TMPDIR}/excl_multi_b.txt"
+printf '10.0.0.0/22\n10.1.0.0/24\n10.1.2.0/24\n' > "${_TMPA}"
+printf '10.0.0.0/24\n10.0.3.128/25\n10.1.0.0/16\n' > "${_TMPB}"
+_RESULT=$(raggre -4 --exclude "${_TMPB}" "${_TMPA}" | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.1.0/24 10.0.2.0/24 10.0.3.0/25 " ] || fail "--exclude multi: got '${_RESULT}'"
+
+# large generated inputs: A - (A - B) must equal A & B, and (A - B) & B must be empty
+for _FAM in 4 6; do
+  _TMPA="${_TMPDIR}/excl_big_a${_FAM}.txt"
+  _TMPB="${_TMPDIR}/excl_big_b${_FAM}.txt"
+  _TMPAB="${_TMPDIR}/excl_big_ab${_FAM}.txt"
+  if [ "${_FAM}" = 4 ]; then
+    ruran4 100000 > "${_TMPA}"
+    ruran4 50000 --prefixes 12-32 > "${_TMPB}"
+  else
+    ruran6 100000 > "${_TMPA}"
+    ruran6 50000 --prefixes 16-64 > "${_TMPB}"
+  fi
+  raggre -${_FAM} --exclude "${_TMPB}" "${_TMPA}" > "${_TMPAB}"
+
+  run
+  raggre -${_FAM} --exclude "${_TMPAB}" "${_TMPA}" | cmp -s - <(raggre -${_FAM} --intersect "${_TMPB}" "${_TMPA}") \
+    || fail "--exclude large v${_FAM}: A - (A - B) differs from A & B"
+
+  run
+  _RESULT=$(raggre -${_FAM} --intersect "${_TMPB}" "${_TMPAB}")
+  [ -z "${_RESULT}" ] || fail "--exclude large v${_FAM}: (A - B) & B is not empty"
+done
+
 # ---------------------------------------------------------------------------
 # --intersect
 # ---------------------------------------------------------------------------
@@ -158,6 +846,45 @@ echo "172.16.0.0/12" > "${_TMPB}"
 _RESULT=$(raggre -4 --intersect "${_TMPB}" "${_TMPA}")
 [ -z "${_RESULT}" ] || fail "--intersect no overlap should be empty, got '${_RESULT}'"
 
+# large generated inputs (uses ruran4/ruran6 found from path)
+_TMPA="${_TMPDIR}/isect_big_a4.txt"
+_TMPB="${_TMPDIR}/isect_big_b4.txt"
+_TMPAB="${_TMPDIR}/isect_big_ab4.txt"
+ruran4 100000 > "${_TMPA}"
+ruran4 50000 --prefixes 12-24 > "${_TMPB}"
+raggre -4 --intersect "${_TMPB}" "${_TMPA}" > "${_TMPAB}"
+
+run
+[ -s "${_TMPAB}" ] || fail "--intersect large v4: empty result"
+
+run
+raggre -4 --intersect "${_TMPA}" "${_TMPB}" | cmp -s - "${_TMPAB}" || fail "--intersect large v4: not commutative"
+
+run
+raggre -4 --intersect "${_TMPA}" "${_TMPA}" | cmp -s - <(raggre -4 "${_TMPA}") || fail "--intersect large v4: A & A differs from A"
+
+run
+raggre -4 --intersect "${_TMPA}" "${_TMPAB}" | cmp -s - "${_TMPAB}" || fail "--intersect large v4: (A & B) & A differs from A & B"
+
+_TMPA="${_TMPDIR}/isect_big_a6.txt"
+_TMPB="${_TMPDIR}/isect_big_b6.txt"
+_TMPAB="${_TMPDIR}/isect_big_ab6.txt"
+ruran6 100000 > "${_TMPA}"
+ruran6 50000 --prefixes 16-48 > "${_TMPB}"
+raggre -6 --intersect "${_TMPB}" "${_TMPA}" > "${_TMPAB}"
+
+run
+[ -s "${_TMPAB}" ] || fail "--intersect large v6: empty result"
+
+run
+raggre -6 --intersect "${_TMPA}" "${_TMPB}" | cmp -s - "${_TMPAB}" || fail "--intersect large v6: not commutative"
+
+run
+raggre -6 --intersect "${_TMPA}" "${_TMPA}" | cmp -s - <(raggre -6 "${_TMPA}") || fail "--intersect large v6: A & A differs from A"
+
+run
+raggre -6 --intersect "${_TMPA}" "${_TMPAB}" | cmp -s - "${_TMPAB}" || fail "--intersect large v6: (A & B) & A differs from A & B"
+
 # ---------------------------------------------------------------------------
 # --diff
 # ---------------------------------------------------------------------------
@@ -244,6 +971,233 @@ run
 _RESULT=$(printf '' | raggre -4 --stats 2>&1 >/dev/null)
 echo "${_RESULT}" | grep -q '\-0' && fail "--stats shows negative zero: ${_RESULT}"
 
+# ---------------------------------------------------------------------------
+# --rejects
+# ---------------------------------------------------------------------------
+
+_TMPA="${_TMPDIR}/rejects_in.txt"
+_TMPB="${_TMPDIR}/rejects_out.txt"
+printf '10.0.0.0/24\n256.1.2.3\n10.0.0.0/33\n\n10.0.0.1/24\n2001:db8::/32\n' > "${_TMPA}"
+raggre -4 --ignore-invalid --rejects "${_TMPB}" "${_TMPA}" > /dev/null
+
+run
+grep -qx "${_TMPA}:2: invalid IPv4 octet \"256\": 256.1.2.3" "${_TMPB}" || fail "--rejects bad octet: $(cat "${_TMPB}")"
+
+run
+grep -qx "${_TMPA}:3: prefix length /33 exceeds /32: 10.0.0.0/33" "${_TMPB}" || fail "--rejects prefix too long: $(cat "${_TMPB}")"
+
+# line numbers count blank lines too
+run
+grep -qx "${_TMPA}:5: host bits set beyond prefix length: 10.0.0.1/24" "${_TMPB}" || fail "--rejects host bits: $(cat "${_TMPB}")"
+
+run
+grep -qx "${_TMPA}:6: address family not selected: 2001:db8::/32" "${_TMPB}" || fail "--rejects wrong family: $(cat "${_TMPB}")"
+
+run
+[ "$(wc -l < "${_TMPB}" | tr -d ' ')" = "4" ] || fail "--rejects should list exactly 4 lines: $(cat "${_TMPB}")"
+
+run
+printf '10.0.0.9-10.0.0.1\n' | raggre -4 --input-range --rejects "${_TMPB}" > /dev/null
+grep -q '^-:1: range start is greater than range end: ' "${_TMPB}" || fail "--rejects range order: $(cat "${_TMPB}")"
+
+run
+printf 'a,10.0.0.0/24\nb,\n' | raggre -4 --csv-field-number 2 --rejects "${_TMPB}" > /dev/null
+grep -qx -- '-:2: empty field: b,' "${_TMPB}" || fail "--rejects csv empty field: $(cat "${_TMPB}")"
+
+# CSV rejects quote the line as written, quotes included
+run
+printf '"x, y",10.0.0.0/24\n"a, b",bad\n' | raggre -4 --csv-field-number 2 --rejects "${_TMPB}" > /dev/null
+grep -qx -- '-:2: invalid address "bad": "a, b",bad' "${_TMPB}" || fail "--rejects csv raw line: $(cat "${_TMPB}")"
+
+# --rejects - writes the diagnostics to stderr; without --rejects they are not shown
+run
+_RESULT=$(printf '10.0.0.0/24\nbad\n' | raggre -4 --rejects - 2>&1 >/dev/null)
+[ "${_RESULT}" = '-:2: invalid address "bad": bad' ] || fail "--rejects -: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '10.0.0.0/24\nbad\n2001:db8::/32\n' | raggre -4 2>&1 >/dev/null)
+[ -z "${_RESULT}" ] || fail "rejects without --rejects should be silent: got '${_RESULT}'"
+
+# ---------------------------------------------------------------------------
+# --strict / --max-invalid / --max-invalid-percent
+# ---------------------------------------------------------------------------
+
+_TMPA="${_TMPDIR}/strict_ok.txt"
+_TMPB="${_TMPDIR}/strict_bad.txt"
+printf '10.0.0.0/24\n10.0.1.0/24\n' > "${_TMPA}"
+printf '10.0.0.0/24\njunk\n10.0.2.0/24\n10.0.3.0/24\n' > "${_TMPB}"
+
+run
+raggre -4 --strict "${_TMPA}" > /dev/null || fail "--strict should pass on clean input"
+
+# lines of the family left out with -4/-6 are filtered, not rejected
+run
+printf '10.0.0.0/24\n2001:db8::/32\n' | raggre -4 --strict > /dev/null || fail "--strict -4 should pass on dual-stack input"
+
+run
+printf '10.0.0.0/24\n2001:db8::/32\n2001:db8:1::/48\n' | raggre -6 --max-invalid-percent 10 > /dev/null || fail "--max-invalid-percent -6 should ignore IPv4 lines"
+
+run
+raggre -4 --strict "${_TMPB}" > /dev/null 2>&1
+_RC=$?
+[ "${_RC}" -eq 3 ] || fail "--strict on invalid input should exit 3, got ${_RC}"
+
+run
+_RESULT=$(raggre -4 --strict "${_TMPB}" 2>&1 >/dev/null)
+echo "${_RESULT}" | grep -q "strict_bad.txt:2: invalid address \"junk\": junk" || fail "--strict should quote the offending line: ${_RESULT}"
+
+run
+raggre -4 --strict --exclude "${_TMPB}" "${_TMPA}" > /dev/null 2>&1
+[ $? -eq 3 ] || fail "--strict should apply to --exclude"
+
+run
+raggre -4 --strict --intersect "${_TMPB}" "${_TMPA}" > /dev/null 2>&1
+[ $? -eq 3 ] || fail "--strict should apply to --intersect"
+
+run
+raggre -4 --strict --diff "${_TMPA}" "${_TMPB}" > /dev/null 2>&1
+[ $? -eq 3 ] || fail "--strict should apply to --diff files"
+
+run
+raggre -4 --max-invalid 1 "${_TMPB}" > /dev/null || fail "--max-invalid 1 should allow one invalid line"
+
+run
+raggre -4 --max-invalid-percent 20 "${_TMPB}" > /dev/null 2>&1
+[ $? -eq 3 ] || fail "--max-invalid-percent 20 should reject 25% invalid lines"
+
+run
+raggre -4 --max-invalid-percent 25 "${_TMPB}" > /dev/null || fail "--max-invalid-percent 25 should allow 25% invalid lines"
+
+run
+raggre -4 --strict --max-invalid 1 < /dev/null 2>/dev/null && fail "--strict + --max-invalid should fail"
+
+# ---------------------------------------------------------------------------
+# --max-entries
+# ---------------------------------------------------------------------------
+
+# the two /24s 7 apart are cheaper to cover than merging with 192.168.0.0/24
+run
+_RESULT=$(printf '10.0.0.0/24\n10.0.7.0/24\n192.168.0.0/24\n' | raggre -4 --max-entries 2 | tr '\n' ' ')
+[ "${_RESULT}" = "10.0.0.0/21 192.168.0.0/24 " ] || fail "--max-entries 2: got '${_RESULT}'"
+
+run
+_RESULT=$(printf '10.0.0.0/24\n10.0.7.0/24\n192.168.0.0/24\n' | raggre -4 --max-entries 2 --stats 2>&1 >/dev/null)
+echo "${_RESULT}" | grep -q '1536 over-covered' || fail "--max-entries --stats over-covered count: ${_RESULT}"
+
+# already within budget: output is the exact aggregation
+run
+_RESULT=$(printf '10.0.0.0/24\n10.0.1.0/24\n' | raggre -4 --max-entries 5)
+[ "${_RESULT}" = "10.0.0.0/23" ] || fail "--max-entries within budget: got '${_RESULT}'"
+
+# large generated inputs: the budget holds per family and the input stays covered
+_TMPA="${_TMPDIR}/lossy_in.txt"
+_TMPB="${_TMPDIR}/lossy_out.txt"
+(ruran4 20000; ruran6 20000) > "${_TMPA}"
+raggre --max-entries 100 "${_TMPA}" > "${_TMPB}"
+
+run
+[ "$(grep -vc : "${_TMPB}")" -le 100 ] || fail "--max-entries 100 v4: too many entries"
+
+run
+[ "$(grep -c : "${_TMPB}")" -le 100 ] || fail "--max-entries 100 v6: too many entries"
+
+run
+_RESULT=$(raggre --exclude "${_TMPB}" "${_TMPA}")
+[ -z "${_RESULT}" ] || fail "--max-entries output should cover all input"
+
+# a budget of one leaves the smallest common supernet of each family
+run
+_RESULT=$(printf '10.0.0.0/24\n10.0.7.0/24\n2001:db8::/48\n2001:db8:1::/48\n192.168.0.0/24\n' | raggre --max-entries 1 --stats 2>&1 | tr '\n' '|')
+[ "${_RESULT}" = "0.0.0.0/0|2001:db8::/47|Lines: 5  Invalid: 0  UTF-8 errors: 0|IPv4: 3 -> 1 aggregated (4294967296 addresses, 4294966528 over-covered)|IPv6: 2 -> 1 aggregated (2417851639229258349412352 addresses, 0 over-covered)|" ] \
+    || fail "--max-entries 1: got '${_RESULT}'"
+
+# over any budget: at most N entries per family, every input covered, and
+# over-coverage equal to output addresses minus input addresses; a budget
+# above the exact count gives the exact aggregation
+_TMPA="${_TMPDIR}/lossy_prop_in.txt"
+_TMPB="${_TMPDIR}/lossy_prop_out.txt"
+ruran4 300 --prefixes 16-32 > "${_TMPA}"
+for _I in $(seq 300); do
+  printf '2001:db8::%x:%x/%d\n' "${RANDOM}" "${RANDOM}" $((112 + RANDOM % 17))
+done >> "${_TMPA}"
+_STATS=$(raggre --stats "${_TMPA}" 2>&1 >/dev/null)
+for _N in 1 2 3 10 64 100000; do
+  _OUT=$(raggre --max-entries "${_N}" --stats "${_TMPA}" 2>&1 > "${_TMPB}")
+  for _FAM in 4 6; do
+    _IN_ADDRS=$(echo "${_STATS}" | sed -n "s/^IPv${_FAM}: .*(\([0-9]*\) addresses)\$/\1/p")
+    _OUT_ADDRS=$(echo "${_OUT}" | sed -n "s/^IPv${_FAM}: .*(\([0-9]*\) addresses, .*/\1/p")
+    _OVER=$(echo "${_OUT}" | sed -n "s/^IPv${_FAM}: .* \([0-9]*\) over-covered)\$/\1/p")
+    if [ "${_FAM}" = 4 ]; then _COUNT=$(grep -vc : "${_TMPB}"); else _COUNT=$(grep -c : "${_TMPB}"); fi
+
+    run
+    [ "${_COUNT}" -le "${_N}" ] || fail "--max-entries ${_N} v${_FAM}: ${_COUNT} entries"
+
+    run
+    [ "${_OVER}" = $((_OUT_ADDRS - _IN_ADDRS)) ] \
+      || fail "--max-entries ${_N} v${_FAM}: ${_OVER} over-covered, but ${_OUT_ADDRS} - ${_IN_ADDRS} addresses"
+  done
+
+  run
+  _RESULT=$(raggre --exclude "${_TMPB}" "${_TMPA}")
+  [ -z "${_RESULT}" ] || fail "--max-entries ${_N}: output does not cover the input"
+done
+
+run
+raggre --max-entries 100000 "${_TMPA}" | cmp -s - <(raggre "${_TMPA}") \
+  || fail "--max-entries above the exact count should give the exact aggregation"
+
+run
+raggre -4 --max-entries 0 < /dev/null 2>/dev/null && fail "--max-entries 0 should fail"
+
+run
+raggre -4 --max-entries 5 --diff "${_TMPA}" "${_TMPB}" < /dev/null 2>/dev/null && fail "--max-entries + --diff should fail"
+
+# ---------------------------------------------------------------------------
+# --promote
+# ---------------------------------------------------------------------------
+
+_TMPA="${_TMPDIR}/promote.txt"
+{ for i in $(seq 1 20); do echo "10.1.2.$i"; done; echo 10.1.3.7; echo 10.1.4.0/25
+  echo 2001:db8:0:1::/64; echo 2001:db8:0:2::1; echo 2001:db8:0:2::5; } > "${_TMPA}"
+
+# 20 hosts reach a count of 16, 1 host does not, half a /24 reaches 50%
+run
+_RESULT=$(raggre -4 --promote 24:16 --promote 24:50% "${_TMPA}" | tr '\n' ' ')
+[ "${_RESULT}" = "10.1.2.0/24 10.1.3.7/32 10.1.4.0/24 " ] || fail "--promote 24:16 24:50%: got '${_RESULT}'"
+
+# levels apply longest prefix first: the promoted /24s count towards the /23s
+run
+_RESULT=$(raggre -4 --promote 23:50% --promote 24:16 "${_TMPA}" | tr '\n' ' ')
+[ "${_RESULT}" = "10.1.2.0/23 10.1.4.0/23 " ] || fail "--promote two levels: got '${_RESULT}'"
+
+# IPv6 counts /64 subnets: the /64 and the two hosts in another /64 make 2
+run
+_RESULT=$(raggre -6 --promote 48:2 "${_TMPA}")
+[ "${_RESULT}" = "2001:db8::/48" ] || fail "--promote 6/48:2: got '${_RESULT}'"
+
+run
+_RESULT=$(raggre -6 --promote 48:3 "${_TMPA}" | wc -l | tr -d ' ')
+[ "${_RESULT}" = "3" ] || fail "--promote 6/48:3 should not promote: got ${_RESULT} lines"
+
+run
+_RESULT=$(raggre --promote 24:16 --promote 6/48:2 --stats "${_TMPA}" 2>&1 >/dev/null)
+echo "${_RESULT}" | grep -q '^IPv4 promoted: 2 blocks (+364 addresses)' || fail "--promote --stats IPv4 summary: ${_RESULT}"
+echo "${_RESULT}" | grep -q '^  10.1.2.0/24: 20 of 256 listed (+236 addresses)' || fail "--promote --stats IPv4 block: ${_RESULT}"
+echo "${_RESULT}" | grep -q '^  2001:db8::/48: 2 of 65536 listed' || fail "--promote --stats IPv6 block: ${_RESULT}"
+
+run
+_RESULT=$(raggre -4 --promote 24:16 --json --stats "${_TMPA}")
+echo "${_RESULT}" | grep -q '"promoted_addresses": "364"' || fail "--promote --json promoted_addresses: ${_RESULT}"
+
+run
+raggre -4 --promote 33:1 < /dev/null 2>/dev/null && fail "--promote 33:1 should fail"
+
+run
+raggre -4 --promote 24:257 < /dev/null 2>/dev/null && fail "--promote 24:257 should fail"
+
+run
+raggre -4 --promote 24:0% < /dev/null 2>/dev/null && fail "--promote 24:0% should fail"
+
 # ---------------------------------------------------------------------------
 # --delimiter and --fields
 # ---------------------------------------------------------------------------
@@ -363,6 +1317,15 @@ run
 _RESULT=$(printf 'a,b\n' | raggre -4 --csv-field-number 99)
 [ -z "${_RESULT}" ] || fail "csv out-of-range column should produce empty output, got '${_RESULT}'"
 
+# a malformed row (wrong field count) is an invalid line, not a crash
+run
+_RESULT=$(printf 'x,y\n1,2,3\n10.0.0.1,a\n' | raggre -4 --csv-field-number 1 --stats 2>&1 | head -2 | tr '\n' '|')
+[ "${_RESULT}" = "10.0.0.1/32|Lines: 3  Invalid: 2  UTF-8 errors: 0|" ] || fail "--csv-field-number malformed row: got '${_RESULT}'"
+
+run
+printf 'x,y\n1,2,3\n' | raggre --csv-field-number 1 --max-invalid 1 > /dev/null 2>&1
+[ $? -eq 3 ] || fail "--max-invalid should count a malformed CSV row"
+
 # CSV with --input-range
 run
 _RESULT=$(printf 'name,10.0.0.0-10.0.0.255\n' | raggre -4 --csv-field-number 2 --input-range)